@@ -0,0 +1,577 @@
+//! Authenticating the requests of a site, with credentials from a secrets file
+//! or the environment
+
+use crate::{
+    config::{AuthConfig, FormLoginConfig, SiteConfig},
+    request::DEFAULT_USER_AGENT,
+    scheduler::{Permit, Scheduler},
+};
+use anyhow::{anyhow, Context};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, COOKIE, LOCATION, SET_COOKIE},
+    redirect::Policy,
+    Client, RequestBuilder, Response, StatusCode, Url,
+};
+use scraper::{Html, Selector};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap, error::Error, fmt, os::unix::fs::PermissionsExt, path::PathBuf,
+    sync::Mutex, time::Duration,
+};
+
+/// Where credentials are looked up by name
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Secrets {
+    /// TOML file with a table of credentials per name
+    path: Option<PathBuf>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Credentials {
+    username: Option<String>,
+    password: Option<String>,
+    token: Option<String>,
+}
+
+/// The authentication of one site, keeping the session of a form login
+#[derive(Debug)]
+pub(crate) struct Auth {
+    scheme: Scheme,
+    /// Cookies set by the responses since the last login
+    session: Mutex<BTreeMap<String, String>>,
+}
+
+enum Scheme {
+    None,
+    Basic { username: String, password: String },
+    Bearer { token: String },
+    Form(Box<FormLogin>),
+}
+
+#[derive(Debug)]
+struct FormLogin {
+    config: FormLoginConfig,
+    login_url: Url,
+    action: Url,
+    /// Credentials are read again on every login, to pick up changed ones
+    secrets: Secrets,
+    /// Does not follow redirects, so the cookies and target of the login
+    /// response can be seen
+    client: Client,
+    timeout: Duration,
+}
+
+/// Logging in failed or did not lead to a session
+#[derive(Debug)]
+pub(crate) struct LoginFailed(String);
+
+impl Secrets {
+    pub(crate) fn new(path: Option<PathBuf>) -> Self {
+        if let Some(path) = &path {
+            match std::fs::metadata(path) {
+                Ok(metadata) if metadata.permissions().mode() & 0o077 != 0 => log::warn!(
+                    "Secrets file {} is accessible by other users",
+                    path.display()
+                ),
+                Ok(_) => (),
+                Err(e) => log::warn!("Failed to read secrets file {}: {}", path.display(), e),
+            }
+        }
+
+        Secrets { path }
+    }
+
+    /// The credentials called `name`, `SITE_CHECKER_<NAME>_USERNAME`,
+    /// `_PASSWORD` and `_TOKEN` override the ones from the secrets file
+    pub(crate) fn credentials(&self, name: &str) -> anyhow::Result<Credentials> {
+        let mut credentials = match &self.path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read secrets file {}", path.display()))?;
+                let mut secrets: BTreeMap<String, Credentials> = toml::from_str(&text)
+                    .with_context(|| format!("Invalid secrets file {}", path.display()))?;
+                secrets.remove(name).unwrap_or_default()
+            }
+            None => Credentials::default(),
+        };
+
+        for (field, value) in [
+            ("USERNAME", &mut credentials.username),
+            ("PASSWORD", &mut credentials.password),
+            ("TOKEN", &mut credentials.token),
+        ] {
+            if let Ok(env) = std::env::var(env_var(name, field)) {
+                *value = Some(env);
+            }
+        }
+
+        Ok(credentials)
+    }
+}
+
+impl Auth {
+    pub(crate) fn new(site: &SiteConfig, secrets: &Secrets) -> anyhow::Result<Self> {
+        let scheme = match &site.auth {
+            None => Scheme::None,
+            Some(AuthConfig::Basic(config)) => {
+                let credentials = secrets.credentials(&config.credentials)?;
+                Scheme::Basic {
+                    username: required(credentials.username, &config.credentials, "USERNAME")?,
+                    password: required(credentials.password, &config.credentials, "PASSWORD")?,
+                }
+            }
+            Some(AuthConfig::Bearer(config)) => {
+                let credentials = secrets.credentials(&config.credentials)?;
+                Scheme::Bearer {
+                    token: required(credentials.token, &config.credentials, "TOKEN")?,
+                }
+            }
+            Some(AuthConfig::Form(config)) => {
+                // Fail at startup rather than on the first login
+                let credentials = secrets.credentials(&config.credentials)?;
+                required(credentials.username, &config.credentials, "USERNAME")?;
+                required(credentials.password, &config.credentials, "PASSWORD")?;
+
+                let login_url = Url::parse(&config.login_url)?;
+                let action = match &config.action {
+                    Some(action) => Url::parse(action)?,
+                    None => login_url.clone(),
+                };
+
+                Scheme::Form(Box::new(FormLogin {
+                    config: config.clone(),
+                    login_url,
+                    action,
+                    secrets: secrets.clone(),
+                    client: Client::builder()
+                        .user_agent(site.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT))
+                        .redirect(Policy::none())
+                        .build()?,
+                    timeout: Duration::from_secs(site.timeout),
+                }))
+            }
+        };
+
+        Ok(Auth {
+            scheme,
+            session: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Send the request `build` makes with the session cookies, every request
+    /// waiting for its turn at `scheduler`. A form login happens before the
+    /// first request and again, once, when a response asks for it.
+    pub(crate) async fn send<F>(
+        &self,
+        scheduler: &Scheduler,
+        build: F,
+    ) -> anyhow::Result<(Response, Permit)>
+    where
+        F: Fn(Option<&str>) -> RequestBuilder,
+    {
+        let login = match &self.scheme {
+            Scheme::None => return scheduler.send(build(None)).await,
+            Scheme::Basic { username, password } => {
+                return scheduler
+                    .send(build(None).basic_auth(username, Some(password)))
+                    .await
+            }
+            Scheme::Bearer { token } => {
+                return scheduler.send(build(None).bearer_auth(token)).await
+            }
+            Scheme::Form(login) => login,
+        };
+
+        if self.cookies().is_none() {
+            self.login(login, scheduler).await?;
+        }
+
+        let (response, permit) = scheduler.send(build(self.cookies().as_deref())).await?;
+        self.store(response.headers());
+        if !login.requested_by(&response) {
+            return Ok((response, permit));
+        }
+        // Logging in may need the slot on the same host
+        drop(permit);
+
+        log::info!("Session expired, logging in at {} again", login.action);
+        self.session.lock().unwrap().clear();
+        self.login(login, scheduler).await?;
+
+        let (response, permit) = scheduler.send(build(self.cookies().as_deref())).await?;
+        self.store(response.headers());
+        if login.requested_by(&response) {
+            return Err(LoginFailed(format!(
+                "still asked to log in after logging in at {}",
+                login.action
+            ))
+            .into());
+        }
+
+        Ok((response, permit))
+    }
+
+    async fn login(&self, login: &FormLogin, scheduler: &Scheduler) -> anyhow::Result<()> {
+        let name = &login.config.credentials;
+        let credentials = login.secrets.credentials(name)?;
+
+        let mut fields = login
+            .config
+            .fields
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect::<Vec<_>>();
+
+        if !login.config.token_fields.is_empty() {
+            let request = login
+                .client
+                .get(login.login_url.clone())
+                .timeout(login.timeout);
+            let (response, _permit) = scheduler.send(self.with_session(request)).await?;
+            self.store(response.headers());
+            fields.extend(login.tokens(&response.text().await?)?);
+        }
+
+        fields.push((
+            login.config.username_field.clone(),
+            required(credentials.username, name, "USERNAME")?,
+        ));
+        fields.push((
+            login.config.password_field.clone(),
+            required(credentials.password, name, "PASSWORD")?,
+        ));
+
+        let request = login
+            .client
+            .post(login.action.clone())
+            .timeout(login.timeout)
+            .form(&fields);
+        let (response, _permit) = scheduler.send(self.with_session(request)).await?;
+        self.store(response.headers());
+
+        let status = response.status();
+        let target = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| login.action.join(location).ok());
+
+        if target.is_some_and(|target| login.is_login_page(&target)) {
+            return Err(LoginFailed(format!(
+                "login at {} redirected back to the login form, check the credentials",
+                login.action
+            ))
+            .into());
+        }
+        if !status.is_success() && !status.is_redirection() {
+            return Err(LoginFailed(format!(
+                "login at {} responded with {}",
+                login.action, status
+            ))
+            .into());
+        }
+        if self.cookies().is_none() {
+            return Err(LoginFailed(format!("login at {} set no cookies", login.action)).into());
+        }
+
+        log::info!("Logged in at {}", login.action);
+        Ok(())
+    }
+
+    /// The session cookies for a `Cookie` header
+    fn cookies(&self) -> Option<String> {
+        let session = self.session.lock().unwrap();
+
+        if session.is_empty() {
+            None
+        } else {
+            Some(
+                session
+                    .iter()
+                    .map(|(name, value)| format!("{}={}", name, value))
+                    .collect::<Vec<_>>()
+                    .join("; "),
+            )
+        }
+    }
+
+    fn with_session(&self, request: RequestBuilder) -> RequestBuilder {
+        match self
+            .cookies()
+            .and_then(|cookies| HeaderValue::from_str(&cookies).ok())
+        {
+            Some(cookies) => request.header(COOKIE, cookies),
+            None => request,
+        }
+    }
+
+    /// Take over the cookies a response sets or deletes. All cookies belong to
+    /// the site, their domain and path are not checked.
+    fn store(&self, headers: &HeaderMap) {
+        let mut session = self.session.lock().unwrap();
+
+        for cookie in headers.get_all(SET_COOKIE) {
+            let cookie = match cookie.to_str() {
+                Ok(cookie) => cookie,
+                Err(_) => continue,
+            };
+            let mut parts = cookie.split(';');
+            let (name, value) = match parts.next().and_then(|pair| pair.split_once('=')) {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => continue,
+            };
+            let deleted = parts.any(|attribute| attribute.trim().eq_ignore_ascii_case("max-age=0"));
+
+            if value.is_empty() || deleted {
+                session.remove(name);
+            } else {
+                session.insert(name.to_string(), value.to_string());
+            }
+        }
+    }
+}
+
+impl FormLogin {
+    /// Whether `response` means the session is gone
+    fn requested_by(&self, response: &Response) -> bool {
+        response.status() == StatusCode::UNAUTHORIZED || self.is_login_page(response.url())
+    }
+
+    fn is_login_page(&self, url: &Url) -> bool {
+        url.host_str() == self.login_url.host_str() && url.path() == self.login_url.path()
+    }
+
+    /// The values of the `token_fields` inputs on the login page
+    fn tokens(&self, page: &str) -> anyhow::Result<Vec<(String, String)>> {
+        let html = Html::parse_document(page);
+
+        self.config
+            .token_fields
+            .iter()
+            .map(|field| {
+                let selector = Selector::parse(&format!("input[name=\"{}\"]", field))
+                    .map_err(|e| anyhow!("Invalid token field '{}': {:?}", field, e))?;
+                let value = html
+                    .select(&selector)
+                    .next()
+                    .and_then(|input| input.value().attr("value"))
+                    .ok_or_else(|| {
+                        LoginFailed(format!("no token field '{}' on {}", field, self.login_url))
+                    })?;
+
+                Ok((field.clone(), value.to_string()))
+            })
+            .collect()
+    }
+}
+
+fn required(value: Option<String>, name: &str, field: &str) -> anyhow::Result<String> {
+    value.with_context(|| {
+        format!(
+            "No {} for credentials '{}' in the secrets file or {}",
+            field.to_lowercase(),
+            name,
+            env_var(name, field)
+        )
+    })
+}
+
+/// `SITE_CHECKER_<NAME>_<FIELD>`, with everything but letters and digits of
+/// the name replaced by `_`
+fn env_var(name: &str, field: &str) -> String {
+    let name = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+
+    format!("SITE_CHECKER_{}_{}", name, field)
+}
+
+/// Passwords and tokens stay out of the logs
+impl fmt::Debug for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scheme::None => f.write_str("None"),
+            Scheme::Basic { username, .. } => f
+                .debug_struct("Basic")
+                .field("username", username)
+                .finish_non_exhaustive(),
+            Scheme::Bearer { .. } => f.debug_struct("Bearer").finish_non_exhaustive(),
+            Scheme::Form(login) => f.debug_tuple("Form").field(login).finish(),
+        }
+    }
+}
+
+impl fmt::Display for LoginFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for LoginFailed {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::PolitenessConfig,
+        test_util::{serve, serve_replies, site, Reply},
+    };
+    use std::io::Write;
+
+    fn secrets() -> (tempfile::NamedTempFile, Secrets) {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            "[login]\nusername = \"u\"\npassword = \"p&q\"\ntoken = \"t\""
+        )
+        .unwrap();
+        let secrets = Secrets::new(Some(file.path().to_path_buf()));
+        (file, secrets)
+    }
+
+    fn scheduler() -> Scheduler {
+        let scheduler = Scheduler::new(Client::new());
+        scheduler.reconfigure(PolitenessConfig {
+            max_concurrent: 1,
+            min_delay: 0,
+            ..Default::default()
+        });
+        scheduler
+    }
+
+    async fn get(auth: &Auth, scheduler: &Scheduler, url: &str) -> StatusCode {
+        let client = Client::new();
+        let (response, _permit) = auth
+            .send(scheduler, |session| {
+                let request = client.get(url);
+                match session {
+                    Some(session) => request.header(COOKIE, session),
+                    None => request,
+                }
+            })
+            .await
+            .unwrap();
+        response.status()
+    }
+
+    #[tokio::test]
+    async fn basic_and_bearer_headers() {
+        let (_file, secrets) = secrets();
+        let scheduler = scheduler();
+        let (url, server) = serve(vec![200, 200], "").await;
+
+        for scheme in ["basic", "bearer"] {
+            let site = site(
+                &url,
+                &format!(
+                    "auth = {{ type = \"{}\", credentials = \"login\" }}",
+                    scheme
+                ),
+            );
+            let auth = Auth::new(&site, &secrets).unwrap();
+            assert_eq!(get(&auth, &scheduler, &url).await, StatusCode::OK);
+        }
+
+        let requests = server.await.unwrap();
+        // base64 of `u:p&q`
+        assert!(requests[0].0.contains("authorization: Basic dTpwJnE=\r\n"));
+        assert!(requests[1].0.contains("authorization: Bearer t\r\n"));
+    }
+
+    #[test]
+    fn missing_credentials() {
+        let (_file, secrets) = secrets();
+        let site = site(
+            "http://example.com/",
+            "auth = { type = \"bearer\", credentials = \"other\" }",
+        );
+
+        let error = Auth::new(&site, &secrets).unwrap_err();
+        assert!(error.to_string().contains("SITE_CHECKER_OTHER_TOKEN"));
+    }
+
+    #[tokio::test]
+    async fn form_login_and_login_again() {
+        let (_file, secrets) = secrets();
+        let scheduler = scheduler();
+        let login_page = Reply {
+            status: 200,
+            headers: vec![("Set-Cookie", "pre=1")],
+            body: "<form><input name=\"csrf\" value=\"abc\"></form>",
+        };
+        let logged_in = |cookie| Reply {
+            status: 302,
+            headers: vec![("Set-Cookie", cookie), ("Location", "/home")],
+            body: "",
+        };
+        let reply = |status| Reply {
+            status,
+            headers: Vec::new(),
+            body: "page",
+        };
+        let (url, server) = serve_replies(vec![
+            login_page,
+            logged_in("session=one; Path=/"),
+            reply(200),
+            reply(401),
+            Reply {
+                status: 200,
+                headers: vec![("Set-Cookie", "pre=2")],
+                body: "<input name=\"csrf\" value=\"def\">",
+            },
+            logged_in("session=two"),
+            reply(200),
+        ])
+        .await;
+
+        let site = site(
+            &url,
+            &format!(
+                "[auth]\ntype = \"form\"\ncredentials = \"login\"\nlogin_url = \"{}/login\"\n\
+                 token_fields = [\"csrf\"]",
+                url
+            ),
+        );
+        let auth = Auth::new(&site, &secrets).unwrap();
+        let page = format!("{}/page", url);
+
+        assert_eq!(get(&auth, &scheduler, &page).await, StatusCode::OK);
+        assert_eq!(get(&auth, &scheduler, &page).await, StatusCode::OK);
+
+        let requests = server.await.unwrap();
+        let lines = requests
+            .iter()
+            .map(|(head, body)| {
+                let line = head.lines().next().unwrap().to_string();
+                let cookie = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("cookie: "))
+                    .unwrap_or_default()
+                    .to_string();
+                (line, cookie, body.as_str())
+            })
+            .collect::<Vec<_>>();
+
+        assert!(lines[0].0.starts_with("GET /login "));
+        assert!(lines[1].0.starts_with("POST /login "));
+        assert_eq!(lines[1].1, "pre=1");
+        assert_eq!(lines[1].2, "csrf=abc&username=u&password=p%26q");
+        assert!(lines[2].0.starts_with("GET /page "));
+        assert_eq!(lines[2].1, "pre=1; session=one");
+        assert_eq!(lines[3].1, "pre=1; session=one");
+
+        // The 401 drops the session and logs in again
+        assert_eq!(lines[4].1, "");
+        assert_eq!(lines[5].2, "csrf=def&username=u&password=p%26q");
+        assert!(lines[6].0.starts_with("GET /page "));
+        assert_eq!(lines[6].1, "pre=2; session=two");
+    }
+}
@@ -0,0 +1,931 @@
+use crate::{diff::OutputFormat, extract::Extractor, request::SiteRequest, template::Templates};
+use anyhow::{bail, Context};
+use chrono::NaiveTime;
+use chrono_tz::Tz;
+use lettre::message::Mailbox;
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Method, Url,
+};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+const DEFAULT_INTERVAL: u64 = 30 * 60;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    /// Notification channels by name, `desktop` and `stdout` exist unless
+    /// they are overridden here
+    #[serde(default)]
+    pub(crate) notifiers: BTreeMap<String, NotifierConfig>,
+
+    /// Limits on the requests to one host
+    #[serde(default)]
+    pub(crate) politeness: PolitenessConfig,
+
+    pub(crate) sites: Vec<SiteConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PolitenessConfig {
+    /// Most requests to one host at the same time
+    #[serde(default = "default_max_concurrent")]
+    pub(crate) max_concurrent: usize,
+
+    /// Seconds between the starts of two requests to one host
+    #[serde(default = "default_min_delay")]
+    pub(crate) min_delay: u64,
+
+    /// Skip urls disallowed by the robots.txt of their host and wait for its
+    /// `Crawl-delay`, sites can override it
+    #[serde(default)]
+    pub(crate) robots: bool,
+
+    /// Other limits for single hosts, e.g. `www.example.com`
+    #[serde(default)]
+    pub(crate) hosts: BTreeMap<String, HostLimits>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct HostLimits {
+    pub(crate) max_concurrent: Option<usize>,
+    pub(crate) min_delay: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SiteConfig {
+    pub(crate) name: String,
+    pub(crate) url: String,
+
+    /// Seconds between two checks of this site
+    #[serde(default = "default_interval")]
+    pub(crate) interval: u64,
+
+    #[serde(default)]
+    pub(crate) headers: BTreeMap<String, String>,
+
+    #[serde(default)]
+    pub(crate) method: RequestMethod,
+
+    /// Sent in the `Cookie` header
+    #[serde(default)]
+    pub(crate) cookies: BTreeMap<String, String>,
+
+    /// Parameters appended to the query string of the url
+    #[serde(default)]
+    pub(crate) query: BTreeMap<String, String>,
+
+    /// Request body, sent url encoded
+    pub(crate) form: Option<BTreeMap<String, String>>,
+
+    /// Request body, sent as JSON
+    pub(crate) json: Option<toml::Value>,
+
+    /// Replaces the `Site Checker` user agent
+    pub(crate) user_agent: Option<String>,
+
+    /// How to authenticate, with credentials kept out of the config
+    pub(crate) auth: Option<AuthConfig>,
+
+    /// Whether to honor robots.txt, overriding `politeness.robots`
+    pub(crate) robots: Option<bool>,
+
+    /// Seconds to wait for the response of one attempt
+    #[serde(default = "default_fetch_timeout")]
+    pub(crate) timeout: u64,
+
+    /// Attempts after a transient failure of the first one
+    #[serde(default = "default_retries")]
+    pub(crate) retries: u32,
+
+    /// Seconds all retries of one check may take together
+    #[serde(default = "default_retry_budget")]
+    pub(crate) retry_budget: u64,
+
+    /// Failed checks in a row after which the site is reported down
+    #[serde(default = "default_down_after")]
+    pub(crate) down_after: u32,
+
+    /// CSS selectors limiting the diff to the matched elements
+    #[serde(default)]
+    pub(crate) selectors: Vec<String>,
+
+    #[serde(default)]
+    pub(crate) mode: DiffMode,
+
+    #[serde(default)]
+    pub(crate) inline: InlineMode,
+
+    /// Format of the diff in notifications and the history
+    #[serde(default)]
+    pub(crate) format: OutputFormat,
+
+    /// Unchanged lines around every change in unified diffs
+    #[serde(default = "default_context")]
+    pub(crate) context: usize,
+
+    /// Send `If-None-Match`/`If-Modified-Since` with the validators of the
+    /// last response
+    #[serde(default = "default_conditional")]
+    pub(crate) conditional: bool,
+
+    /// Names of built-in normalizers to apply before diffing
+    #[serde(default)]
+    pub(crate) normalize: Vec<String>,
+
+    /// Regex rules removing or rewriting content before diffing
+    #[serde(default)]
+    pub(crate) ignore: Vec<IgnoreRule>,
+
+    /// Names of the notifiers changes of this site are sent to
+    #[serde(default = "default_notify")]
+    pub(crate) notify: Vec<String>,
+
+    /// Templates of the notification title and body, overriding the ones of
+    /// the notifiers
+    pub(crate) title: Option<String>,
+    pub(crate) body: Option<String>,
+}
+
+/// HTTP method a site is requested with
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "UPPERCASE")]
+pub(crate) enum RequestMethod {
+    #[default]
+    Get,
+    Head,
+    Post,
+    Put,
+    Patch,
+    Delete,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum AuthConfig {
+    /// HTTP Basic authentication with a username and password
+    Basic(CredentialsConfig),
+    /// An `Authorization: Bearer` token
+    Bearer(CredentialsConfig),
+    /// A session cookie from posting a username and password to a login form
+    Form(FormLoginConfig),
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CredentialsConfig {
+    /// Name of the credentials in the secrets file or environment
+    pub(crate) credentials: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct FormLoginConfig {
+    /// Name of the credentials in the secrets file or environment
+    pub(crate) credentials: String,
+
+    /// The page with the login form, being redirected to it means the
+    /// session expired
+    pub(crate) login_url: String,
+
+    /// Where the form is posted to, `login_url` by default
+    pub(crate) action: Option<String>,
+
+    #[serde(default = "default_username_field")]
+    pub(crate) username_field: String,
+
+    #[serde(default = "default_password_field")]
+    pub(crate) password_field: String,
+
+    /// Hidden inputs of the login form sent along, e.g. CSRF tokens
+    #[serde(default)]
+    pub(crate) token_fields: Vec<String>,
+
+    /// Further fields sent with the credentials
+    #[serde(default)]
+    pub(crate) fields: BTreeMap<String, String>,
+}
+
+/// What part of the response gets compared between two checks
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DiffMode {
+    /// The HTML markup, line by line
+    #[default]
+    Html,
+    /// The visible text, one line per block element
+    Text,
+    /// The parsed element tree, reporting changes per element
+    Dom,
+}
+
+/// How replaced lines are refined in line based diffs
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum InlineMode {
+    /// Mark the changed words
+    #[default]
+    Word,
+    /// Mark the changed characters
+    Char,
+    /// Show the whole old and new lines
+    None,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub(crate) enum IgnoreRule {
+    /// Remove everything matching the pattern
+    Pattern(String),
+    /// Replace everything matching the pattern, `$1` refers to capture groups
+    Replace { pattern: String, replace: String },
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub(crate) struct NotifierConfig {
+    #[serde(flatten)]
+    pub(crate) kind: NotifierKind,
+
+    /// Time of day during which notifications are held back
+    pub(crate) quiet_hours: Option<QuietHours>,
+
+    /// Most notifications per period, more are held back and sent together
+    pub(crate) rate_limit: Option<RateLimit>,
+
+    /// Templates of the notification title and body
+    pub(crate) title: Option<String>,
+    pub(crate) body: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum NotifierKind {
+    /// A freedesktop notification over D-Bus
+    Desktop(DesktopConfig),
+    /// A JSON POST request
+    Webhook(WebhookConfig),
+    /// A mail sent through an SMTP relay
+    Email(EmailConfig),
+    /// A command run for every change
+    Exec(ExecConfig),
+    /// The title and diff printed to stdout
+    Stdout,
+    /// A Slack message with blocks, through an incoming webhook
+    Slack(ChatWebhookConfig),
+    /// A Mattermost post, through an incoming webhook
+    Mattermost(ChatWebhookConfig),
+    /// A Discord embed, through a channel webhook
+    Discord(DiscordConfig),
+    /// A message in a Matrix room
+    Matrix(MatrixConfig),
+    /// A summary of the changes of all sites per hour or day, sent through
+    /// other notifiers
+    Digest(DigestConfig),
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct QuietHours {
+    /// Local time the quiet hours start and end at, `HH:MM`, they span
+    /// midnight if `end` is before `start`
+    pub(crate) start: String,
+    pub(crate) end: String,
+
+    /// IANA time zone, e.g. `Europe/Zurich`, the system time zone by default
+    pub(crate) timezone: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RateLimit {
+    pub(crate) max: u32,
+
+    /// Seconds
+    pub(crate) period: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DesktopConfig {
+    #[serde(default = "default_icon")]
+    pub(crate) icon: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct WebhookConfig {
+    pub(crate) url: String,
+
+    #[serde(default)]
+    pub(crate) headers: BTreeMap<String, String>,
+
+    /// Key for the HMAC-SHA256 signature sent in `X-Signature-256`
+    pub(crate) secret: Option<String>,
+
+    /// Seconds to wait for a response
+    #[serde(default = "default_webhook_timeout")]
+    pub(crate) timeout: u64,
+
+    /// Attempts after the first one failed
+    #[serde(default = "default_retries")]
+    pub(crate) retries: u32,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct EmailConfig {
+    pub(crate) host: String,
+
+    /// Defaults to the standard port of the `tls` mode
+    pub(crate) port: Option<u16>,
+
+    #[serde(default)]
+    pub(crate) tls: SmtpTls,
+
+    pub(crate) username: Option<String>,
+    pub(crate) password: Option<String>,
+
+    pub(crate) from: String,
+    pub(crate) to: Vec<String>,
+
+    /// Seconds to collect changes of other sites before sending one mail
+    #[serde(default = "default_batch")]
+    pub(crate) batch: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SmtpTls {
+    /// Plain text, for a local relay or a test sink
+    None,
+    /// Upgrade the connection with `STARTTLS`
+    #[default]
+    Starttls,
+    /// Connect with TLS right away
+    Tls,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ChatWebhookConfig {
+    pub(crate) url: String,
+
+    /// Link to the full diff of a cut off message, `{change}`, `{snapshot}`
+    /// and `{site}` are replaced with the change id, fetch id and site name
+    pub(crate) link: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DiscordConfig {
+    pub(crate) url: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct MatrixConfig {
+    pub(crate) homeserver: String,
+
+    /// Room id, e.g. `!abc:example.org`
+    pub(crate) room: String,
+
+    /// Access token of the posting user
+    pub(crate) token: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DigestConfig {
+    #[serde(default)]
+    pub(crate) window: DigestWindow,
+
+    /// Local time of day daily digests are sent at, `HH:MM`
+    #[serde(default = "default_digest_at")]
+    pub(crate) at: String,
+
+    /// Notifiers the digest is sent through
+    pub(crate) to: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DigestWindow {
+    /// At every full hour
+    Hourly,
+    /// Once a day at `at`
+    #[default]
+    Daily,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ExecConfig {
+    /// The program and its arguments
+    pub(crate) command: Vec<String>,
+
+    /// Seconds after which the command is killed
+    #[serde(default = "default_exec_timeout")]
+    pub(crate) timeout: u64,
+}
+
+impl Config {
+    pub(crate) fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+
+        let mut config: Config = toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+
+        config
+            .notifiers
+            .entry("desktop".to_string())
+            .or_insert_with(|| {
+                NotifierConfig::new(NotifierKind::Desktop(DesktopConfig {
+                    icon: default_icon(),
+                }))
+            });
+        config
+            .notifiers
+            .entry("stdout".to_string())
+            .or_insert_with(|| NotifierConfig::new(NotifierKind::Stdout));
+
+        config
+            .validate()
+            .with_context(|| format!("Invalid config file {}", path.display()))?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.sites.is_empty() {
+            bail!("No sites configured");
+        }
+
+        self.politeness.validate().context("Invalid politeness")?;
+
+        for (name, notifier) in &self.notifiers {
+            notifier
+                .validate()
+                .with_context(|| format!("Invalid notifier '{}'", name))?;
+
+            if let NotifierKind::Digest(digest) = &notifier.kind {
+                for to in &digest.to {
+                    match self.notifiers.get(to).map(|to| &to.kind) {
+                        None => bail!("Digest '{}' uses the unknown notifier '{}'", name, to),
+                        Some(NotifierKind::Digest(_)) => {
+                            bail!("Digest '{}' cannot be sent through digest '{}'", name, to)
+                        }
+                        Some(_) => (),
+                    }
+                }
+            }
+        }
+
+        let mut names = HashSet::new();
+        let mut slugs = HashSet::new();
+
+        for site in &self.sites {
+            site.validate()
+                .with_context(|| format!("Invalid site '{}'", site.name))?;
+
+            for notifier in &site.notify {
+                if !self.notifiers.contains_key(notifier) {
+                    bail!(
+                        "Site '{}' uses the unknown notifier '{}'",
+                        site.name,
+                        notifier
+                    );
+                }
+            }
+
+            if !names.insert(site.name.as_str()) {
+                bail!("Site '{}' is configured more than once", site.name);
+            }
+
+            if !slugs.insert(site.slug()) {
+                bail!(
+                    "Site '{}' shares its snapshot file '{}' with another site, rename one of them",
+                    site.name,
+                    site.slug()
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl PolitenessConfig {
+    /// The concurrency and delay limits of `host`
+    pub(crate) fn limits(&self, host: &str) -> (usize, Duration) {
+        let limits = self.hosts.get(host);
+
+        (
+            limits
+                .and_then(|limits| limits.max_concurrent)
+                .unwrap_or(self.max_concurrent),
+            Duration::from_secs(
+                limits
+                    .and_then(|limits| limits.min_delay)
+                    .unwrap_or(self.min_delay),
+            ),
+        )
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.max_concurrent == 0 {
+            bail!("Max concurrent must be greater than zero");
+        }
+
+        for (host, limits) in &self.hosts {
+            if limits.max_concurrent == Some(0) {
+                bail!(
+                    "Max concurrent of host '{}' must be greater than zero",
+                    host
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for PolitenessConfig {
+    fn default() -> Self {
+        PolitenessConfig {
+            max_concurrent: default_max_concurrent(),
+            min_delay: default_min_delay(),
+            robots: false,
+            hosts: BTreeMap::new(),
+        }
+    }
+}
+
+impl AuthConfig {
+    fn validate(&self) -> anyhow::Result<()> {
+        let credentials = match self {
+            AuthConfig::Basic(config) | AuthConfig::Bearer(config) => &config.credentials,
+            AuthConfig::Form(config) => {
+                validate_url(&config.login_url)?;
+                if let Some(action) = &config.action {
+                    validate_url(action)?;
+                }
+                &config.credentials
+            }
+        };
+
+        if credentials.is_empty() {
+            bail!("Credentials name must not be empty");
+        }
+
+        Ok(())
+    }
+}
+
+impl RequestMethod {
+    pub(crate) fn method(self) -> Method {
+        match self {
+            RequestMethod::Get => Method::GET,
+            RequestMethod::Head => Method::HEAD,
+            RequestMethod::Post => Method::POST,
+            RequestMethod::Put => Method::PUT,
+            RequestMethod::Patch => Method::PATCH,
+            RequestMethod::Delete => Method::DELETE,
+        }
+    }
+}
+
+impl SiteConfig {
+    pub(crate) fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval)
+    }
+
+    /// File system friendly version of the site name
+    pub(crate) fn slug(&self) -> String {
+        let slug = self
+            .name
+            .to_lowercase()
+            .chars()
+            .map(|c| if c.is_alphanumeric() { c } else { '-' })
+            .collect::<String>();
+
+        slug.split('-')
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+
+    pub(crate) fn header_map(&self) -> anyhow::Result<HeaderMap> {
+        header_map(&self.headers)
+    }
+
+    pub(crate) fn templates(&self) -> anyhow::Result<Option<Arc<Templates>>> {
+        Ok(Templates::new(self.title.as_deref(), self.body.as_deref())?.map(Arc::new))
+    }
+
+    /// Whether `other` logs in like this site, so its `Auth` and login
+    /// session can be kept
+    pub(crate) fn same_login(&self, other: &SiteConfig) -> bool {
+        self.auth == other.auth
+            && self.user_agent == other.user_agent
+            && self.timeout == other.timeout
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.slug().is_empty() {
+            bail!("Site name must contain at least one letter or digit");
+        }
+
+        validate_url(&self.url)?;
+
+        if self.interval == 0 {
+            bail!("Interval must be greater than zero");
+        }
+        if self.timeout == 0 {
+            bail!("Timeout must be greater than zero");
+        }
+        if self.down_after == 0 {
+            bail!("Down after must be greater than zero");
+        }
+
+        SiteRequest::new(self)?;
+        Extractor::new(self)?;
+        if let Some(auth) = &self.auth {
+            auth.validate().context("Invalid auth")?;
+        }
+        Templates::validate(self.title.as_deref(), self.body.as_deref())?;
+
+        Ok(())
+    }
+}
+
+impl NotifierConfig {
+    pub(crate) fn new(kind: NotifierKind) -> Self {
+        NotifierConfig {
+            kind,
+            quiet_hours: None,
+            rate_limit: None,
+            title: None,
+            body: None,
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        self.kind.validate()?;
+
+        if let NotifierKind::Digest(_) = self.kind {
+            if self.quiet_hours.is_some() || self.rate_limit.is_some() {
+                bail!(
+                    "Digests have no quiet hours or rate limit, set them on the notifiers in 'to'"
+                );
+            }
+            if self.title.is_some() || self.body.is_some() {
+                bail!("Digests have no templates, they are sent with a title and summary of their own");
+            }
+        }
+
+        Templates::validate(self.title.as_deref(), self.body.as_deref())?;
+
+        if let Some(quiet_hours) = &self.quiet_hours {
+            quiet_hours.validate().context("Invalid quiet hours")?;
+        }
+
+        if let Some(limit) = &self.rate_limit {
+            if limit.max == 0 || limit.period == 0 {
+                bail!("Rate limit max and period must be greater than zero");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl NotifierKind {
+    fn validate(&self) -> anyhow::Result<()> {
+        match self {
+            NotifierKind::Webhook(webhook) => webhook.validate(),
+            NotifierKind::Email(email) => email.validate(),
+            NotifierKind::Exec(exec) => {
+                if exec.command.is_empty() {
+                    bail!("Command must not be empty");
+                }
+                if exec.timeout == 0 {
+                    bail!("Timeout must be greater than zero");
+                }
+                Ok(())
+            }
+            NotifierKind::Slack(chat) | NotifierKind::Mattermost(chat) => validate_url(&chat.url),
+            NotifierKind::Discord(discord) => validate_url(&discord.url),
+            NotifierKind::Matrix(matrix) => {
+                validate_url(&matrix.homeserver)?;
+
+                if !matrix.room.starts_with('!') || !matrix.room.contains(':') {
+                    bail!("Room must be a room id like '!abc:example.org'");
+                }
+                if matrix.token.is_empty() {
+                    bail!("Token must not be empty");
+                }
+
+                Ok(())
+            }
+            NotifierKind::Digest(digest) => {
+                if digest.to.is_empty() {
+                    bail!("No notifiers to send the digest through configured");
+                }
+                digest.at()?;
+                Ok(())
+            }
+            NotifierKind::Desktop(_) | NotifierKind::Stdout => Ok(()),
+        }
+    }
+}
+
+impl QuietHours {
+    pub(crate) fn times(&self) -> anyhow::Result<(NaiveTime, NaiveTime)> {
+        Ok((parse_time(&self.start)?, parse_time(&self.end)?))
+    }
+
+    pub(crate) fn timezone(&self) -> anyhow::Result<Option<Tz>> {
+        self.timezone
+            .as_deref()
+            .map(|timezone| {
+                timezone
+                    .parse::<Tz>()
+                    .map_err(|_| anyhow::anyhow!("Unknown time zone '{}'", timezone))
+            })
+            .transpose()
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let (start, end) = self.times()?;
+        if start == end {
+            bail!("Quiet hours must not start and end at the same time");
+        }
+
+        self.timezone()?;
+
+        Ok(())
+    }
+}
+
+impl DigestConfig {
+    /// The time of day daily digests are sent at
+    pub(crate) fn at(&self) -> anyhow::Result<NaiveTime> {
+        parse_time(&self.at)
+    }
+}
+
+impl WebhookConfig {
+    pub(crate) fn header_map(&self) -> anyhow::Result<HeaderMap> {
+        header_map(&self.headers)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        validate_url(&self.url)?;
+
+        if self.timeout == 0 {
+            bail!("Timeout must be greater than zero");
+        }
+
+        self.header_map()?;
+
+        Ok(())
+    }
+}
+
+impl EmailConfig {
+    pub(crate) fn port(&self) -> u16 {
+        self.port.unwrap_or(match self.tls {
+            SmtpTls::None => 25,
+            SmtpTls::Starttls => 587,
+            SmtpTls::Tls => 465,
+        })
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        self.from
+            .parse::<Mailbox>()
+            .with_context(|| format!("Invalid sender '{}'", self.from))?;
+
+        if self.to.is_empty() {
+            bail!("No recipients configured");
+        }
+
+        for to in &self.to {
+            to.parse::<Mailbox>()
+                .with_context(|| format!("Invalid recipient '{}'", to))?;
+        }
+
+        if self.username.is_some() != self.password.is_some() {
+            bail!("Username and password must be set together");
+        }
+
+        Ok(())
+    }
+}
+
+fn header_map(headers: &BTreeMap<String, String>) -> anyhow::Result<HeaderMap> {
+    let mut map = HeaderMap::new();
+
+    for (name, value) in headers {
+        let header_name = HeaderName::from_bytes(name.as_bytes())
+            .with_context(|| format!("Invalid header name '{}'", name))?;
+        let header_value = HeaderValue::from_str(value)
+            .with_context(|| format!("Invalid value for header '{}'", name))?;
+
+        map.insert(header_name, header_value);
+    }
+
+    Ok(map)
+}
+
+fn parse_time(time: &str) -> anyhow::Result<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .with_context(|| format!("Invalid time of day '{}', expected HH:MM", time))
+}
+
+fn validate_url(url: &str) -> anyhow::Result<()> {
+    let parsed = Url::parse(url).with_context(|| format!("Invalid url '{}'", url))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        bail!("Unsupported url scheme '{}'", parsed.scheme());
+    }
+
+    Ok(())
+}
+
+fn default_interval() -> u64 {
+    DEFAULT_INTERVAL
+}
+
+fn default_context() -> usize {
+    3
+}
+
+fn default_fetch_timeout() -> u64 {
+    30
+}
+
+fn default_retry_budget() -> u64 {
+    120
+}
+
+fn default_down_after() -> u32 {
+    2
+}
+
+fn default_max_concurrent() -> usize {
+    2
+}
+
+fn default_min_delay() -> u64 {
+    1
+}
+
+fn default_username_field() -> String {
+    "username".to_string()
+}
+
+fn default_password_field() -> String {
+    "password".to_string()
+}
+
+fn default_conditional() -> bool {
+    true
+}
+
+fn default_webhook_timeout() -> u64 {
+    10
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+fn default_exec_timeout() -> u64 {
+    30
+}
+
+fn default_batch() -> u64 {
+    10
+}
+
+fn default_digest_at() -> String {
+    "08:00".to_string()
+}
+
+fn default_notify() -> Vec<String> {
+    vec!["desktop".to_string()]
+}
+
+fn default_icon() -> String {
+    "appointment".to_string()
+}
@@ -0,0 +1,490 @@
+use crate::{
+    config::{DiffMode, InlineMode},
+    dom::{self, DomChange},
+};
+use anyhow::bail;
+use chrono::{Local, TimeZone};
+use prettydiff::{basic::DiffOp, diff_chars, diff_lines, diff_words};
+use serde::Deserialize;
+use std::str::FromStr;
+
+/// The content changes between two results, kept structured so every output
+/// format can render it its own way
+#[derive(Debug)]
+pub(crate) enum ContentDiff {
+    Lines(LineDiff),
+    Dom(Vec<DomChange>),
+}
+
+#[derive(Debug)]
+pub(crate) struct LineDiff {
+    ops: Vec<LineOp>,
+    inline: InlineMode,
+}
+
+#[derive(Debug)]
+enum LineOp {
+    Equal(Vec<String>),
+    Insert(Vec<String>),
+    Remove(Vec<String>),
+    Replace(Vec<String>, Vec<String>),
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct DiffStats {
+    pub(crate) added: usize,
+    pub(crate) removed: usize,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum OutputFormat {
+    /// `Added:`/`Removed:`/`Changed:` blocks with inline markers
+    #[default]
+    Text,
+    /// An HTML fragment using `<del>` and `<ins>`
+    Html,
+    /// A unified diff, as produced by `diff -u`
+    Unified,
+}
+
+/// Where the compared contents come from, for the headers of a unified diff
+pub(crate) struct DiffHeader<'a> {
+    pub(crate) site: &'a str,
+    pub(crate) old_time: i64,
+    pub(crate) new_time: i64,
+    /// Unchanged lines shown around every change
+    pub(crate) context: usize,
+}
+
+/// A piece of a replaced line after the inline refinement pass
+enum Fragment<'a> {
+    Equal(String),
+    Insert(String),
+    Remove(String),
+    Line(&'a str, &'a str),
+}
+
+impl ContentDiff {
+    /// Compare `old` and `new`, `None` if there is no difference
+    pub(crate) fn new(old: &str, new: &str, mode: DiffMode, inline: InlineMode) -> Option<Self> {
+        if mode == DiffMode::Dom {
+            let changes = dom::diff(old, new);
+
+            if changes.is_empty() {
+                return None;
+            }
+
+            return Some(ContentDiff::Dom(changes));
+        }
+
+        let changeset = diff_lines(old, new);
+
+        let ops: Vec<LineOp> = changeset
+            .diff()
+            .into_iter()
+            .map(|op| match op {
+                DiffOp::Equal(a) => LineOp::Equal(to_owned(a)),
+                DiffOp::Insert(a) => LineOp::Insert(to_owned(a)),
+                DiffOp::Remove(a) => LineOp::Remove(to_owned(a)),
+                DiffOp::Replace(a, b) => LineOp::Replace(to_owned(a), to_owned(b)),
+            })
+            .collect();
+
+        if ops.iter().all(|op| matches!(op, LineOp::Equal(_))) {
+            return None;
+        }
+
+        Some(ContentDiff::Lines(LineDiff { ops, inline }))
+    }
+
+    /// Render in the given format, DOM diffs have no line structure and are
+    /// rendered as text instead of a unified diff
+    pub(crate) fn render(&self, format: OutputFormat, header: &DiffHeader<'_>) -> String {
+        match (format, self) {
+            (OutputFormat::Text, _) => self.render_text(),
+            (OutputFormat::Html, _) => self.render_html(),
+            (OutputFormat::Unified, ContentDiff::Lines(lines)) => lines.render_unified(header),
+            (OutputFormat::Unified, ContentDiff::Dom(_)) => self.render_text(),
+        }
+    }
+
+    /// Number of added and removed lines, or of changed elements
+    pub(crate) fn size(&self) -> usize {
+        match self {
+            ContentDiff::Lines(lines) => lines
+                .ops
+                .iter()
+                .map(|op| match op {
+                    LineOp::Equal(_) => 0,
+                    LineOp::Insert(a) | LineOp::Remove(a) => a.len(),
+                    LineOp::Replace(a, b) => a.len() + b.len(),
+                })
+                .sum(),
+            ContentDiff::Dom(changes) => changes.len(),
+        }
+    }
+
+    /// Added and removed lines or elements, modified elements count as both
+    pub(crate) fn stats(&self) -> DiffStats {
+        let mut stats = DiffStats::default();
+
+        match self {
+            ContentDiff::Lines(lines) => {
+                for op in &lines.ops {
+                    match op {
+                        LineOp::Equal(_) => (),
+                        LineOp::Insert(added) => stats.added += added.len(),
+                        LineOp::Remove(removed) => stats.removed += removed.len(),
+                        LineOp::Replace(removed, added) => {
+                            stats.removed += removed.len();
+                            stats.added += added.len();
+                        }
+                    }
+                }
+            }
+            ContentDiff::Dom(changes) => {
+                for change in changes {
+                    match change {
+                        DomChange::Added { .. } => stats.added += 1,
+                        DomChange::Removed { .. } => stats.removed += 1,
+                        DomChange::Modified { .. } => {
+                            stats.added += 1;
+                            stats.removed += 1;
+                        }
+                    }
+                }
+            }
+        }
+
+        stats
+    }
+
+    /// Plain text, with `[-removed-]{+added+}` markers inside changed lines
+    pub(crate) fn render_text(&self) -> String {
+        match self {
+            ContentDiff::Lines(lines) => lines.render_text(),
+            ContentDiff::Dom(changes) => dom::render(changes),
+        }
+    }
+
+    /// An HTML fragment, with `<del>` and `<ins>` marking the changes
+    pub(crate) fn render_html(&self) -> String {
+        match self {
+            ContentDiff::Lines(lines) => lines.render_html(),
+            ContentDiff::Dom(changes) => dom::render_html(changes),
+        }
+    }
+}
+
+impl LineDiff {
+    fn render_text(&self) -> String {
+        self.ops.iter().fold(String::new(), |acc, op| match op {
+            LineOp::Equal(_) => acc,
+            LineOp::Insert(a) => acc + "Added:\n" + &a.join("\n") + "\n\n",
+            LineOp::Remove(a) => acc + "Removed:\n" + &a.join("\n") + "\n\n",
+            LineOp::Replace(a, b) if self.inline == InlineMode::None => {
+                acc + "Replaced:\n" + &a.join("\n") + "\nwith\n" + &b.join("\n") + "\n\n"
+            }
+            LineOp::Replace(a, b) => {
+                let lines = refine(a, b, self.inline)
+                    .into_iter()
+                    .map(|line| {
+                        line.into_iter()
+                            .map(|fragment| match fragment {
+                                Fragment::Equal(s) => s,
+                                Fragment::Insert(s) => format!("{{+{}+}}", s),
+                                Fragment::Remove(s) => format!("[-{}-]", s),
+                                Fragment::Line(old, new) => format!("[-{}-]{{+{}+}}", old, new),
+                            })
+                            .collect::<String>()
+                    })
+                    .collect::<Vec<_>>();
+
+                acc + "Changed:\n" + &lines.join("\n") + "\n\n"
+            }
+        })
+    }
+
+    fn render_html(&self) -> String {
+        let body = self.ops.iter().fold(String::new(), |acc, op| match op {
+            LineOp::Equal(_) => acc,
+            LineOp::Insert(a) => {
+                acc + "<h4>Added</h4>\n<pre><ins>" + &escape_lines(a) + "</ins></pre>\n"
+            }
+            LineOp::Remove(a) => {
+                acc + "<h4>Removed</h4>\n<pre><del>" + &escape_lines(a) + "</del></pre>\n"
+            }
+            LineOp::Replace(a, b) if self.inline == InlineMode::None => {
+                acc + "<h4>Replaced</h4>\n<pre><del>"
+                    + &escape_lines(a)
+                    + "</del>\n<ins>"
+                    + &escape_lines(b)
+                    + "</ins></pre>\n"
+            }
+            LineOp::Replace(a, b) => {
+                let lines = refine(a, b, self.inline)
+                    .into_iter()
+                    .map(|line| {
+                        line.into_iter()
+                            .map(|fragment| match fragment {
+                                Fragment::Equal(s) => escape(&s),
+                                Fragment::Insert(s) => format!("<ins>{}</ins>", escape(&s)),
+                                Fragment::Remove(s) => format!("<del>{}</del>", escape(&s)),
+                                Fragment::Line(old, new) => {
+                                    format!("<del>{}</del><ins>{}</ins>", escape(old), escape(new))
+                                }
+                            })
+                            .collect::<String>()
+                    })
+                    .collect::<Vec<_>>();
+
+                acc + "<h4>Changed</h4>\n<pre>" + &lines.join("\n") + "</pre>\n"
+            }
+        });
+
+        format!("<div class=\"diff\">\n{}</div>\n", body)
+    }
+
+    fn render_unified(&self, header: &DiffHeader<'_>) -> String {
+        let mut lines: Vec<(char, &str)> = Vec::new();
+
+        for op in &self.ops {
+            match op {
+                LineOp::Equal(a) => lines.extend(a.iter().map(|l| (' ', l.as_str()))),
+                LineOp::Insert(a) => lines.extend(a.iter().map(|l| ('+', l.as_str()))),
+                LineOp::Remove(a) => lines.extend(a.iter().map(|l| ('-', l.as_str()))),
+                LineOp::Replace(a, b) => {
+                    lines.extend(a.iter().map(|l| ('-', l.as_str())));
+                    lines.extend(b.iter().map(|l| ('+', l.as_str())));
+                }
+            }
+        }
+
+        let changed: Vec<usize> = lines
+            .iter()
+            .enumerate()
+            .filter(|(_, (kind, _))| *kind != ' ')
+            .map(|(idx, _)| idx)
+            .collect();
+
+        // Group the changed lines into hunks, merging changes whose context
+        // would overlap
+        let context = header.context;
+        let mut hunks: Vec<(usize, usize)> = Vec::new();
+
+        for idx in changed {
+            let start = idx.saturating_sub(context);
+            let end = (idx + context + 1).min(lines.len());
+
+            match hunks.last_mut() {
+                Some(last) if start <= last.1 => last.1 = end,
+                _ => hunks.push((start, end)),
+            }
+        }
+
+        let mut out = format!(
+            "--- {}\t{}\n+++ {}\t{}\n",
+            header.site,
+            format_time(header.old_time),
+            header.site,
+            format_time(header.new_time)
+        );
+
+        // Line numbers in the old and new content before every line
+        let mut old_line = 1;
+        let mut new_line = 1;
+        let mut position = 0;
+
+        for (start, end) in hunks {
+            for (kind, _) in &lines[position..start] {
+                advance(*kind, &mut old_line, &mut new_line);
+            }
+
+            let old_count = lines[start..end].iter().filter(|(k, _)| *k != '+').count();
+            let new_count = lines[start..end].iter().filter(|(k, _)| *k != '-').count();
+
+            out += &format!(
+                "@@ -{} +{} @@\n",
+                hunk_range(old_line, old_count),
+                hunk_range(new_line, new_count)
+            );
+
+            for (kind, line) in &lines[start..end] {
+                out.push(*kind);
+                out.push_str(line);
+                out.push('\n');
+                advance(*kind, &mut old_line, &mut new_line);
+            }
+
+            position = end;
+        }
+
+        out
+    }
+}
+
+fn advance(kind: char, old_line: &mut usize, new_line: &mut usize) {
+    if kind != '+' {
+        *old_line += 1;
+    }
+    if kind != '-' {
+        *new_line += 1;
+    }
+}
+
+/// `start,count` of a hunk, an empty range starts at the line before it
+fn hunk_range(start: usize, count: usize) -> String {
+    match count {
+        0 => format!("{},0", start - 1),
+        1 => format!("{}", start),
+        _ => format!("{},{}", start, count),
+    }
+}
+
+fn format_time(timestamp: i64) -> String {
+    match Local.timestamp_opt(timestamp, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S %z").to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "html" => Ok(OutputFormat::Html),
+            "unified" => Ok(OutputFormat::Unified),
+            _ => bail!("Unknown format '{}', expected text, html or unified", s),
+        }
+    }
+}
+
+/// Pair up the old and new lines of a replaced block and diff every pair by
+/// word or character, lines without a partner are kept whole
+fn refine<'a>(old: &'a [String], new: &'a [String], inline: InlineMode) -> Vec<Vec<Fragment<'a>>> {
+    let mut lines = Vec::new();
+
+    for (old, new) in old.iter().zip(new.iter()) {
+        let changeset = match inline {
+            InlineMode::Char => diff_chars(old, new),
+            _ => diff_words(old, new),
+        };
+
+        let fragments = changeset
+            .diff()
+            .into_iter()
+            .flat_map(|op| match op {
+                DiffOp::Equal(a) => vec![Fragment::Equal(a.concat())],
+                DiffOp::Insert(a) => vec![Fragment::Insert(a.concat())],
+                DiffOp::Remove(a) => vec![Fragment::Remove(a.concat())],
+                DiffOp::Replace(a, b) => {
+                    vec![Fragment::Remove(a.concat()), Fragment::Insert(b.concat())]
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // An inline diff of two mostly unrelated lines is harder to read than
+        // both lines
+        let unchanged: usize = fragments
+            .iter()
+            .map(|fragment| match fragment {
+                Fragment::Equal(s) => s.trim().len(),
+                _ => 0,
+            })
+            .sum();
+
+        if unchanged * 2 < old.trim().len().max(new.trim().len()) {
+            lines.push(vec![Fragment::Line(old, new)]);
+        } else {
+            lines.push(fragments);
+        }
+    }
+
+    for old in old.iter().skip(new.len()) {
+        lines.push(vec![Fragment::Remove(old.clone())]);
+    }
+
+    for new in new.iter().skip(old.len()) {
+        lines.push(vec![Fragment::Insert(new.clone())]);
+    }
+
+    lines
+}
+
+fn to_owned(lines: &[&str]) -> Vec<String> {
+    lines.iter().map(|line| line.to_string()).collect()
+}
+
+fn escape_lines(lines: &[String]) -> String {
+    escape(&lines.join("\n"))
+}
+
+pub(crate) fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD: &str = "a\nb\nc\nd\ne\nf\ng";
+
+    /// The hunks of the unified diff, without the `---`/`+++` header
+    fn hunks(old: &str, new: &str) -> String {
+        let header = DiffHeader {
+            site: "site",
+            old_time: 0,
+            new_time: 0,
+            context: 1,
+        };
+        let unified = ContentDiff::new(old, new, DiffMode::Html, InlineMode::Word)
+            .unwrap()
+            .render(OutputFormat::Unified, &header);
+
+        unified
+            .lines()
+            .skip(2)
+            .map(|line| line.to_string() + "\n")
+            .collect()
+    }
+
+    #[test]
+    fn unified_single_change() {
+        assert_eq!(
+            hunks(OLD, "a\nb\nC\nd\ne\nf\ng"),
+            "@@ -2,3 +2,3 @@\n b\n-c\n+C\n d\n"
+        );
+    }
+
+    #[test]
+    fn unified_merges_overlapping_context() {
+        assert_eq!(
+            hunks(OLD, "a\nB\nc\nD\ne\nf\ng"),
+            "@@ -1,5 +1,5 @@\n a\n-b\n+B\n c\n-d\n+D\n e\n"
+        );
+    }
+
+    #[test]
+    fn unified_separate_hunks() {
+        assert_eq!(
+            hunks(OLD, "A\nb\nc\nd\ne\nf\nG"),
+            "@@ -1,2 +1,2 @@\n-a\n+A\n b\n@@ -6,2 +6,2 @@\n f\n-g\n+G\n"
+        );
+    }
+
+    #[test]
+    fn unified_insert_into_empty() {
+        assert_eq!(hunks("", "a\nb"), "@@ -0,0 +1,2 @@\n+a\n+b\n");
+    }
+
+    #[test]
+    fn unified_remove_everything() {
+        assert_eq!(hunks("a\nb", ""), "@@ -1,2 +0,0 @@\n-a\n-b\n");
+    }
+}
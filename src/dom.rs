@@ -0,0 +1,479 @@
+use crate::{diff::escape, extract::HIDDEN};
+use scraper::{ElementRef, Html, Node};
+use std::{collections::BTreeMap, ops::Range};
+
+/// Longest text shown for an added or removed element
+const SUMMARY_LEN: usize = 200;
+/// Most cells of the table aligning siblings, longer lists of differing
+/// siblings are compared by tag in order instead
+const MAX_ALIGN_CELLS: usize = 250_000;
+
+/// Owned, simplified copy of an element: whitespace-only text, comments and
+/// invisible elements are dropped
+#[derive(Debug)]
+struct DomNode {
+    tag: String,
+    attrs: BTreeMap<String, String>,
+    text: String,
+    children: Vec<DomNode>,
+    /// Position among all element siblings, hidden ones included, for
+    /// `:nth-child`
+    position: usize,
+}
+
+/// One element-level difference between two documents
+#[derive(Debug)]
+pub(crate) enum DomChange {
+    Added {
+        path: String,
+        summary: String,
+    },
+    Removed {
+        path: String,
+        summary: String,
+    },
+    Modified {
+        path: String,
+        text: Option<(String, String)>,
+        attrs: Vec<(String, Option<String>, Option<String>)>,
+    },
+}
+
+/// Compare two HTML documents element by element.
+///
+/// Children are aligned by tag, id and class, so an inserted element shows up
+/// as one `Added` change instead of shifting every following sibling.
+pub(crate) fn diff(old: &str, new: &str) -> Vec<DomChange> {
+    let old = DomNode::parse(old);
+    let new = DomNode::parse(new);
+
+    let mut changes = Vec::new();
+    diff_children(&old.children, &new.children, "", &mut changes);
+    changes
+}
+
+pub(crate) fn render(changes: &[DomChange]) -> String {
+    changes
+        .iter()
+        .fold(String::new(), |acc, change| match change {
+            DomChange::Added { path, summary } => acc + "Added " + path + ":\n" + summary + "\n\n",
+            DomChange::Removed { path, summary } => {
+                acc + "Removed " + path + ":\n" + summary + "\n\n"
+            }
+            DomChange::Modified { path, text, attrs } => {
+                let mut acc = acc + "Modified " + path + ":\n";
+
+                if let Some((old, new)) = text {
+                    acc += &format!("text: {:?} -> {:?}\n", old, new);
+                }
+
+                for (name, old, new) in attrs {
+                    acc += &match (old, new) {
+                        (Some(old), Some(new)) => format!("{}: {:?} -> {:?}\n", name, old, new),
+                        (None, Some(new)) => format!("{}: added {:?}\n", name, new),
+                        (Some(old), None) => format!("{}: removed {:?}\n", name, old),
+                        (None, None) => String::new(),
+                    };
+                }
+
+                acc + "\n"
+            }
+        })
+}
+
+pub(crate) fn render_html(changes: &[DomChange]) -> String {
+    let body = changes
+        .iter()
+        .fold(String::new(), |acc, change| match change {
+            DomChange::Added { path, summary } => {
+                acc + "<h4>Added <code>"
+                    + &escape(path)
+                    + "</code></h4>\n<pre><ins>"
+                    + &escape(summary)
+                    + "</ins></pre>\n"
+            }
+            DomChange::Removed { path, summary } => {
+                acc + "<h4>Removed <code>"
+                    + &escape(path)
+                    + "</code></h4>\n<pre><del>"
+                    + &escape(summary)
+                    + "</del></pre>\n"
+            }
+            DomChange::Modified { path, text, attrs } => {
+                let mut acc = acc + "<h4>Modified <code>" + &escape(path) + "</code></h4>\n<pre>";
+
+                if let Some((old, new)) = text {
+                    acc += &format!(
+                        "text: <del>{}</del> <ins>{}</ins>\n",
+                        escape(old),
+                        escape(new)
+                    );
+                }
+
+                for (name, old, new) in attrs {
+                    acc += &format!(
+                        "{}: <del>{}</del> <ins>{}</ins>\n",
+                        escape(name),
+                        old.as_deref().map(escape).unwrap_or_default(),
+                        new.as_deref().map(escape).unwrap_or_default()
+                    );
+                }
+
+                acc + "</pre>\n"
+            }
+        });
+
+    format!("<div class=\"diff\">\n{}</div>\n", body)
+}
+
+impl DomNode {
+    fn parse(html: &str) -> Self {
+        let document = Html::parse_document(html);
+        DomNode::from_element(document.root_element(), 1)
+    }
+
+    fn from_element(element: ElementRef<'_>, position: usize) -> Self {
+        let mut text = String::new();
+        let mut children = Vec::new();
+        let mut elements = 0;
+
+        for child in element.children() {
+            if let Some(child) = ElementRef::wrap(child) {
+                elements += 1;
+                if !HIDDEN.contains(&child.value().name()) {
+                    children.push(DomNode::from_element(child, elements));
+                }
+            } else if let Node::Text(t) = child.value() {
+                text.push_str(t);
+                text.push(' ');
+            }
+        }
+
+        DomNode {
+            tag: element.value().name().to_string(),
+            attrs: element
+                .value()
+                .attrs()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            text: collapse(&text),
+            children,
+            position,
+        }
+    }
+
+    /// Identity used to align siblings of the old and new document
+    fn key(&self) -> (&str, Option<&str>, Option<&str>) {
+        (
+            &self.tag,
+            self.attrs.get("id").map(String::as_str),
+            self.attrs.get("class").map(String::as_str),
+        )
+    }
+
+    /// Everything this element displays, truncated for reports
+    fn summary(&self) -> String {
+        let mut text = String::new();
+        self.collect_text(&mut text);
+        let text = collapse(&text);
+
+        if text.is_empty() {
+            let attrs = self
+                .attrs
+                .iter()
+                .map(|(name, value)| format!(" {}=\"{}\"", name, value))
+                .collect::<String>();
+            return format!("<{}{}>", self.tag, attrs);
+        }
+
+        match text.char_indices().nth(SUMMARY_LEN) {
+            Some((idx, _)) => format!("{}…", &text[..idx]),
+            None => text,
+        }
+    }
+
+    fn collect_text(&self, out: &mut String) {
+        out.push_str(&self.text);
+        out.push(' ');
+
+        for child in &self.children {
+            child.collect_text(out);
+        }
+    }
+}
+
+fn diff_children(old: &[DomNode], new: &[DomNode], parent: &str, changes: &mut Vec<DomChange>) {
+    let pairs = align(old, new);
+
+    let (mut i, mut j) = (0, 0);
+
+    for (oi, nj) in pairs
+        .into_iter()
+        .chain(std::iter::once((old.len(), new.len())))
+    {
+        diff_gap(old, i..oi, new, j..nj, parent, changes);
+
+        if oi < old.len() && nj < new.len() {
+            diff_node(&old[oi], &new[nj], &child_path(parent, new, nj), changes);
+        }
+
+        i = oi + 1;
+        j = nj + 1;
+    }
+}
+
+/// Siblings between two aligned pairs: elements with the same tag are compared
+/// in order, the rest are reported as removed or added
+fn diff_gap(
+    old: &[DomNode],
+    old_range: Range<usize>,
+    new: &[DomNode],
+    new_range: Range<usize>,
+    parent: &str,
+    changes: &mut Vec<DomChange>,
+) {
+    let mut unmatched: Vec<usize> = new_range.collect();
+
+    for oi in old_range {
+        let matched = unmatched
+            .iter()
+            .position(|nj| new[*nj].tag == old[oi].tag)
+            .map(|pos| unmatched.remove(pos));
+
+        match matched {
+            Some(nj) => diff_node(&old[oi], &new[nj], &child_path(parent, new, nj), changes),
+            None => changes.push(DomChange::Removed {
+                path: child_path(parent, old, oi),
+                summary: old[oi].summary(),
+            }),
+        }
+    }
+
+    for nj in unmatched {
+        changes.push(DomChange::Added {
+            path: child_path(parent, new, nj),
+            summary: new[nj].summary(),
+        });
+    }
+}
+
+fn diff_node(old: &DomNode, new: &DomNode, path: &str, changes: &mut Vec<DomChange>) {
+    let text = if old.text != new.text {
+        Some((old.text.clone(), new.text.clone()))
+    } else {
+        None
+    };
+
+    let mut attrs = Vec::new();
+    for (name, value) in &old.attrs {
+        match new.attrs.get(name) {
+            Some(new_value) if new_value == value => (),
+            new_value => attrs.push((name.clone(), Some(value.clone()), new_value.cloned())),
+        }
+    }
+    for (name, value) in &new.attrs {
+        if !old.attrs.contains_key(name) {
+            attrs.push((name.clone(), None, Some(value.clone())));
+        }
+    }
+
+    if text.is_some() || !attrs.is_empty() {
+        changes.push(DomChange::Modified {
+            path: path.to_string(),
+            text,
+            attrs,
+        });
+    }
+
+    diff_children(&old.children, &new.children, path, changes);
+}
+
+/// Index pairs of the longest common subsequence of `old` and `new` by key.
+/// The common prefix and suffix are paired up front, what lies between only
+/// when it fits in `MAX_ALIGN_CELLS`.
+fn align(old: &[DomNode], new: &[DomNode]) -> Vec<(usize, usize)> {
+    let same = |(old, new): &(&DomNode, &DomNode)| old.key() == new.key();
+    let prefix = old.iter().zip(new).take_while(same).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(same)
+        .count();
+
+    let (old_end, new_end) = (old.len() - suffix, new.len() - suffix);
+    let mut pairs = (0..prefix).map(|i| (i, i)).collect::<Vec<_>>();
+
+    if (old_end - prefix) * (new_end - prefix) <= MAX_ALIGN_CELLS {
+        pairs.extend(
+            lcs(&old[prefix..old_end], &new[prefix..new_end])
+                .into_iter()
+                .map(|(i, j)| (i + prefix, j + prefix)),
+        );
+    }
+
+    pairs.extend((0..suffix).map(|k| (old_end + k, new_end + k)));
+    pairs
+}
+
+fn lcs(old: &[DomNode], new: &[DomNode]) -> Vec<(usize, usize)> {
+    let (n, m) = (old.len(), new.len());
+    let mut lengths = vec![vec![0usize; m + 1]; n + 1];
+
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lengths[i][j] = if old[i].key() == new[j].key() {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut pairs = Vec::new();
+    let (mut i, mut j) = (0, 0);
+
+    while i < n && j < m {
+        if old[i].key() == new[j].key() {
+            pairs.push((i, j));
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+
+    pairs
+}
+
+/// CSS-like path of `siblings[idx]`, with `:nth-child` only where the tag
+/// alone is ambiguous
+fn child_path(parent: &str, siblings: &[DomNode], idx: usize) -> String {
+    let node = &siblings[idx];
+
+    let segment = if let Some(id) = node.attrs.get("id") {
+        format!("{}#{}", node.tag, id)
+    } else if siblings.iter().filter(|n| n.tag == node.tag).count() > 1 {
+        format!("{}:nth-child({})", node.tag, node.position)
+    } else {
+        node.tag.clone()
+    };
+
+    if parent.is_empty() {
+        segment
+    } else {
+        format!("{} > {}", parent, segment)
+    }
+}
+
+fn collapse(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(old: &str, new: &str) -> Vec<String> {
+        diff(old, new)
+            .into_iter()
+            .map(|change| match change {
+                DomChange::Added { path, summary } => format!("+{} {}", path, summary),
+                DomChange::Removed { path, summary } => format!("-{} {}", path, summary),
+                DomChange::Modified { path, text, .. } => {
+                    let (old, new) = text.unwrap_or_default();
+                    format!("~{} {} -> {}", path, old, new)
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn inserted_sibling_is_one_change() {
+        assert_eq!(
+            paths(
+                "<ul><li>a</li><li>b</li><li>c</li></ul>",
+                "<ul><li>a</li><li class=\"new\">x</li><li>b</li><li>c</li></ul>",
+            ),
+            ["+body > ul > li:nth-child(2) x"]
+        );
+    }
+
+    #[test]
+    fn removed_sibling_is_one_change() {
+        assert_eq!(
+            paths(
+                "<div><p id=\"a\">a</p><p id=\"b\">b</p><p id=\"c\">c</p></div>",
+                "<div><p id=\"a\">a</p><p id=\"c\">c</p></div>",
+            ),
+            ["-body > div > p#b b"]
+        );
+    }
+
+    #[test]
+    fn reordered_siblings() {
+        assert_eq!(
+            paths(
+                "<div><h1>title</h1><p>text</p></div>",
+                "<div><p>text</p><h1>title</h1></div>",
+            ),
+            ["-body > div > h1 title", "+body > div > h1 title"]
+        );
+    }
+
+    #[test]
+    fn modified_text_and_attributes() {
+        let changes = diff(
+            "<p class=\"a\" title=\"t\">old</p>",
+            "<p class=\"a\" lang=\"en\">new</p>",
+        );
+
+        match &changes[..] {
+            [DomChange::Modified { path, text, attrs }] => {
+                assert_eq!(path, "body > p");
+                assert_eq!(text, &Some(("old".to_string(), "new".to_string())));
+                assert_eq!(
+                    attrs,
+                    &[
+                        ("title".to_string(), Some("t".to_string()), None),
+                        ("lang".to_string(), None, Some("en".to_string())),
+                    ]
+                );
+            }
+            changes => panic!("{:?}", changes),
+        }
+    }
+
+    #[test]
+    fn nth_child_counts_hidden_siblings() {
+        assert_eq!(
+            paths(
+                "<div><script>x()</script><p>a</p><p>b</p></div>",
+                "<div><script>x()</script><p>a</p><p>c</p></div>",
+            ),
+            ["~body > div > p:nth-child(3) b -> c"]
+        );
+    }
+
+    #[test]
+    fn long_sibling_lists_skip_the_table() {
+        let list = |items: &[usize]| {
+            let items = items
+                .iter()
+                .map(|i| format!("<li class=\"i{}\">{}</li>", i, i))
+                .collect::<String>();
+            format!("<ul><li>first</li>{}<li>last</li></ul>", items)
+        };
+        let old = (0..1000).collect::<Vec<_>>();
+        let new = (0..1000).rev().collect::<Vec<_>>();
+
+        let old = DomNode::parse(&list(&old));
+        let new = DomNode::parse(&list(&new));
+        let (old, new) = (&old.children[1].children[0], &new.children[1].children[0]);
+
+        // Only the unchanged first and last items are paired
+        assert_eq!(align(&old.children, &new.children), [(0, 0), (1001, 1001)]);
+    }
+}
@@ -0,0 +1,16 @@
+//! Formatting errors for log lines and notifications
+
+use std::error::Error;
+
+/// `error` and all its sources on one line
+pub(crate) fn describe(error: &dyn Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+
+    while let Some(e) = source {
+        message += &format!(": {}", e);
+        source = e.source();
+    }
+
+    message
+}
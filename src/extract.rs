@@ -0,0 +1,250 @@
+use crate::{
+    config::{DiffMode, SiteConfig},
+    normalize::Normalizer,
+};
+use anyhow::anyhow;
+use scraper::{ElementRef, Html, Node, Selector};
+
+/// Elements whose contents are never visible
+pub(crate) static HIDDEN: &[&str] = &["script", "style", "noscript", "template"];
+
+/// Elements starting a new line of text
+static BLOCKS: &[&str] = &[
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "br",
+    "caption",
+    "dd",
+    "details",
+    "div",
+    "dl",
+    "dt",
+    "fieldset",
+    "figcaption",
+    "figure",
+    "footer",
+    "form",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "hr",
+    "li",
+    "main",
+    "nav",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "summary",
+    "table",
+    "td",
+    "th",
+    "title",
+    "tr",
+    "ul",
+];
+
+/// Turns a response body into the text that gets diffed.
+///
+/// Without selectors the whole body is used, otherwise only the elements
+/// matched by any of the selectors, in document order per selector. In
+/// `DiffMode::Text` the markup is dropped and only the visible text is kept,
+/// `DiffMode::Dom` keeps the markup for the structural diff.
+/// The result is passed through the site's `Normalizer`.
+#[derive(Debug)]
+pub(crate) struct Extractor {
+    selectors: Vec<Selector>,
+    mode: DiffMode,
+    pub(crate) normalizer: Normalizer,
+}
+
+impl Extractor {
+    pub(crate) fn new(site: &SiteConfig) -> anyhow::Result<Self> {
+        let selectors = site
+            .selectors
+            .iter()
+            .map(|s| Selector::parse(s).map_err(|e| anyhow!("Invalid selector '{}': {:?}", s, e)))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Extractor {
+            selectors,
+            mode: site.mode,
+            normalizer: Normalizer::new(site)?,
+        })
+    }
+
+    pub(crate) fn mode(&self) -> DiffMode {
+        self.mode
+    }
+
+    pub(crate) fn extract(&self, bytes: &[u8]) -> String {
+        let selected = self.select(bytes);
+
+        self.normalizer.apply(&selected).into_owned()
+    }
+
+    /// The selected content, before normalization
+    pub(crate) fn select(&self, bytes: &[u8]) -> String {
+        let body = String::from_utf8_lossy(bytes);
+
+        if self.selectors.is_empty() && self.mode != DiffMode::Text {
+            return body.into_owned();
+        }
+
+        let document = Html::parse_document(&body);
+
+        let elements: Vec<ElementRef<'_>> = if self.selectors.is_empty() {
+            vec![document.root_element()]
+        } else {
+            self.selectors
+                .iter()
+                .flat_map(|selector| document.select(selector))
+                .collect()
+        };
+
+        match self.mode {
+            DiffMode::Html | DiffMode::Dom => elements
+                .into_iter()
+                .map(|element| element.html())
+                .collect::<Vec<_>>()
+                .join("\n"),
+            DiffMode::Text => {
+                let mut blocks = Vec::new();
+                let mut current = String::new();
+
+                for element in elements {
+                    collect_text(element, &mut blocks, &mut current);
+                    flush(&mut blocks, &mut current);
+                }
+
+                blocks.join("\n")
+            }
+        }
+    }
+}
+
+fn collect_text(element: ElementRef<'_>, blocks: &mut Vec<String>, current: &mut String) {
+    for child in element.children() {
+        if let Some(child) = ElementRef::wrap(child) {
+            let name = child.value().name();
+
+            if HIDDEN.contains(&name) {
+                continue;
+            }
+
+            let block = BLOCKS.contains(&name);
+
+            if block {
+                flush(blocks, current);
+            }
+
+            collect_text(child, blocks, current);
+
+            if block {
+                flush(blocks, current);
+            }
+        } else if let Node::Text(text) = child.value() {
+            current.push_str(text);
+            current.push(' ');
+        }
+    }
+}
+
+/// Finish the current block, collapsing its whitespace
+fn flush(blocks: &mut Vec<String>, current: &mut String) {
+    let text = current.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    if !text.is_empty() {
+        blocks.push(text);
+    }
+
+    current.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::site;
+
+    fn extractor(toml: &str) -> Extractor {
+        Extractor::new(&site("http://example.com/", toml)).unwrap()
+    }
+
+    const PAGE: &str = "<html><head><title>Page</title></head><body>\
+        <div class=\"ad\">Buy now</div>\
+        <article><h1>News</h1><p>First</p></article>\
+        <aside><p>Side</p></aside>\
+        <article><p>Second</p></article>\
+        </body></html>";
+
+    #[test]
+    fn without_selectors_keeps_the_body() {
+        assert_eq!(extractor("").extract(PAGE.as_bytes()), PAGE);
+    }
+
+    #[test]
+    fn selectors_pick_the_matched_elements() {
+        let extractor = extractor("selectors = [\"article p\", \"h1\"]");
+
+        assert_eq!(
+            extractor.extract(PAGE.as_bytes()),
+            "<p>First</p>\n<p>Second</p>\n<h1>News</h1>"
+        );
+        // Nothing outside the selection counts
+        let changed = PAGE.replace("Buy now", "Sale").replace("Side", "Other");
+        assert_eq!(
+            extractor.extract(changed.as_bytes()),
+            extractor.extract(PAGE.as_bytes())
+        );
+    }
+
+    #[test]
+    fn no_match_is_empty() {
+        assert_eq!(
+            extractor("selectors = [\"#missing\"]").extract(PAGE.as_bytes()),
+            ""
+        );
+    }
+
+    #[test]
+    fn invalid_selector() {
+        let site = site("http://example.com/", "selectors = [\"p[\"]");
+        let error = Extractor::new(&site).unwrap_err();
+
+        assert!(error.to_string().contains("Invalid selector 'p['"));
+    }
+
+    #[test]
+    fn text_mode_keeps_visible_blocks() {
+        let extractor = extractor("mode = \"text\"");
+        let page = "<html><head><title>Page</title><style>p { color: red }</style></head>\
+            <body><script>track()</script><div>  Hello\n   <b>big</b>   world </div>\
+            <ul><li>One</li><li>Two<br>lines</li></ul><noscript>Enable JS</noscript>\
+            <template><p>Later</p></template></body></html>";
+
+        assert_eq!(
+            extractor.extract(page.as_bytes()),
+            "Page\nHello big world\nOne\nTwo\nlines"
+        );
+    }
+
+    #[test]
+    fn text_mode_ignores_markup_changes() {
+        let extractor = extractor("mode = \"text\"\nselectors = [\"main\"]");
+        let old = "<main><p class=\"a\">Text</p><script src=\"app.1.js\"></script></main>";
+        let new =
+            "<main><div><p class=\"b\">Text</p></div><script src=\"app.2.js\"></script></main>";
+
+        assert_eq!(extractor.extract(old.as_bytes()), "Text");
+        assert_eq!(
+            extractor.extract(old.as_bytes()),
+            extractor.extract(new.as_bytes())
+        );
+    }
+}
@@ -0,0 +1,295 @@
+//! Classifying failed fetches and retrying the transient ones
+
+use crate::auth::LoginFailed;
+use chrono::{DateTime, Utc};
+use reqwest::{
+    header::{HeaderMap, RETRY_AFTER},
+    StatusCode,
+};
+use std::{
+    error::Error,
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// Delay before the first retry of a fetch, doubled after every further one
+const BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Why a fetch failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum FailureKind {
+    Dns,
+    Connect,
+    Tls,
+    Timeout,
+    /// A `5xx` response
+    Server,
+    /// A `429` response
+    RateLimited,
+    /// A `403`, `404` or `410` response, the page is not available
+    Unavailable,
+    /// The login of the site failed
+    Auth,
+    /// Anything else, e.g. too many redirects
+    Other,
+}
+
+/// A fetch that failed on every attempt
+#[derive(Debug)]
+pub(crate) struct FetchError {
+    pub(crate) kind: FailureKind,
+    pub(crate) attempts: u32,
+    source: anyhow::Error,
+}
+
+/// Jittered exponential delays between the attempts of one check, within a
+/// maximum number of retries and a time budget
+#[derive(Debug)]
+pub(crate) struct Backoff {
+    retries: u32,
+    attempts: u32,
+    deadline: Instant,
+    delay: Duration,
+}
+
+impl FailureKind {
+    pub(crate) fn of(error: &anyhow::Error) -> Self {
+        if error.is::<LoginFailed>() {
+            return FailureKind::Auth;
+        }
+
+        let error = match error.downcast_ref::<reqwest::Error>() {
+            Some(error) => error,
+            None => return FailureKind::Other,
+        };
+
+        if error.is_timeout() {
+            return FailureKind::Timeout;
+        }
+
+        // Neither hyper nor rustls errors are exposed by reqwest, their
+        // messages are all there is
+        let mut messages = Vec::new();
+        let mut source = error.source();
+        while let Some(e) = source {
+            messages.push(e.to_string().to_lowercase());
+            source = e.source();
+        }
+        let mentions = |words: &[&str]| {
+            messages
+                .iter()
+                .any(|message| words.iter().any(|word| message.contains(word)))
+        };
+
+        if mentions(&[
+            "dns error",
+            "failed to lookup address",
+            "name or service not known",
+        ]) {
+            FailureKind::Dns
+        } else if mentions(&["certificate", "tls", "ssl", "handshake"]) {
+            FailureKind::Tls
+        } else if mentions(&["timed out"]) {
+            FailureKind::Timeout
+        } else if error.is_connect() || error.is_body() || error.is_request() {
+            FailureKind::Connect
+        } else {
+            FailureKind::Other
+        }
+    }
+
+    /// The failure a response status stands for, if any
+    pub(crate) fn of_status(status: StatusCode) -> Option<Self> {
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            Some(FailureKind::RateLimited)
+        } else if status.is_server_error() {
+            Some(FailureKind::Server)
+        } else if matches!(
+            status,
+            StatusCode::FORBIDDEN | StatusCode::NOT_FOUND | StatusCode::GONE
+        ) {
+            Some(FailureKind::Unavailable)
+        } else {
+            None
+        }
+    }
+
+    /// Whether another attempt may succeed, certificate problems, rejected
+    /// logins, missing pages and invalid requests do not go away on their own
+    pub(crate) fn is_transient(self) -> bool {
+        !matches!(
+            self,
+            FailureKind::Tls | FailureKind::Auth | FailureKind::Unavailable | FailureKind::Other
+        )
+    }
+}
+
+impl FetchError {
+    pub(crate) fn new(kind: FailureKind, attempts: u32, source: anyhow::Error) -> Self {
+        FetchError {
+            kind,
+            attempts,
+            source,
+        }
+    }
+}
+
+impl Backoff {
+    pub(crate) fn new(retries: u32, budget: Duration) -> Self {
+        Backoff {
+            retries,
+            attempts: 1,
+            deadline: Instant::now() + budget,
+            delay: BASE_DELAY,
+        }
+    }
+
+    pub(crate) fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// How long to wait before the next attempt, `None` when out of retries
+    /// or when waiting would exceed the budget. `retry_after` replaces the
+    /// exponential delay.
+    pub(crate) fn next(&mut self, retry_after: Option<Duration>) -> Option<Duration> {
+        if self.attempts > self.retries {
+            return None;
+        }
+
+        let delay = retry_after.unwrap_or_else(|| self.delay.mul_f64(0.5 + fastrand::f64() / 2.0));
+        if Instant::now() + delay > self.deadline {
+            return None;
+        }
+
+        self.attempts += 1;
+        self.delay *= 2;
+
+        Some(delay)
+    }
+}
+
+/// The `Retry-After` of a response, in seconds or as HTTP date
+pub(crate) fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FailureKind::Dns => "DNS lookup failed",
+            FailureKind::Connect => "connection failed",
+            FailureKind::Tls => "TLS handshake failed",
+            FailureKind::Timeout => "timed out",
+            FailureKind::Server => "server error",
+            FailureKind::RateLimited => "rate limited",
+            FailureKind::Unavailable => "page unavailable",
+            FailureKind::Auth => "login failed",
+            FailureKind::Other => "request failed",
+        })
+    }
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.kind == FailureKind::Auth {
+            write!(f, "{}: {}", self.kind, self.source)
+        } else if self.attempts == 1 {
+            write!(f, "{}", self.kind)
+        } else {
+            write!(f, "{} after {} attempts", self.kind, self.attempts)
+        }
+    }
+}
+
+impl Error for FetchError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn classifies_statuses() {
+        assert_eq!(FailureKind::of_status(StatusCode::OK), None);
+        assert_eq!(FailureKind::of_status(StatusCode::NOT_MODIFIED), None);
+        assert_eq!(FailureKind::of_status(StatusCode::BAD_REQUEST), None);
+        assert_eq!(
+            FailureKind::of_status(StatusCode::TOO_MANY_REQUESTS),
+            Some(FailureKind::RateLimited)
+        );
+        assert_eq!(
+            FailureKind::of_status(StatusCode::BAD_GATEWAY),
+            Some(FailureKind::Server)
+        );
+
+        for status in [
+            StatusCode::FORBIDDEN,
+            StatusCode::NOT_FOUND,
+            StatusCode::GONE,
+        ] {
+            let kind = FailureKind::of_status(status);
+            assert_eq!(kind, Some(FailureKind::Unavailable));
+            assert!(!kind.unwrap().is_transient());
+        }
+
+        assert!(FailureKind::Server.is_transient());
+        assert!(FailureKind::RateLimited.is_transient());
+        assert!(!FailureKind::Auth.is_transient());
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter() {
+        let mut backoff = Backoff::new(4, Duration::from_secs(3600));
+
+        for attempt in 0..4 {
+            let base = BASE_DELAY * 2u32.pow(attempt);
+            let delay = backoff.next(None).unwrap();
+            assert!(delay >= base / 2 && delay <= base, "{:?}", delay);
+        }
+
+        assert_eq!(backoff.attempts(), 5);
+        assert_eq!(backoff.next(None), None);
+    }
+
+    #[test]
+    fn backoff_stays_within_budget() {
+        let mut backoff = Backoff::new(10, Duration::from_secs(5));
+
+        assert_eq!(
+            backoff.next(Some(Duration::from_secs(2))),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(backoff.next(Some(Duration::from_secs(10))), None);
+        assert_eq!(backoff.attempts(), 2);
+    }
+
+    #[test]
+    fn parses_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("120"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        assert_eq!(retry_after(&headers), Some(Duration::ZERO));
+    }
+}
@@ -0,0 +1,187 @@
+//! Whether a site is reachable, judged by its consecutive failed checks
+
+use std::time::{Duration, SystemTime};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum HealthState {
+    Up,
+    /// Failing, but not yet for `down_after` checks in a row
+    Degraded,
+    Down,
+    /// Responding again after being down, up with the next successful check
+    Recovered,
+}
+
+/// A change of the health worth a notification
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Transition {
+    WentDown { since: SystemTime },
+    Recovered { since: SystemTime, outage: Duration },
+}
+
+#[derive(Debug)]
+pub(crate) struct Health {
+    state: HealthState,
+    down_after: u32,
+    failures: u32,
+    /// Time of the first failed check in a row
+    since: Option<SystemTime>,
+}
+
+impl Health {
+    pub(crate) fn new(down_after: u32) -> Self {
+        Health {
+            state: HealthState::Up,
+            down_after,
+            failures: 0,
+            since: None,
+        }
+    }
+
+    pub(crate) fn state(&self) -> HealthState {
+        self.state
+    }
+
+    pub(crate) fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub(crate) fn set_down_after(&mut self, down_after: u32) {
+        self.down_after = down_after;
+    }
+
+    pub(crate) fn failure(&mut self, at: SystemTime) -> Option<Transition> {
+        self.failures += 1;
+        let since = *self.since.get_or_insert(at);
+
+        match self.state {
+            HealthState::Down => None,
+            _ if self.failures >= self.down_after => {
+                self.state = HealthState::Down;
+                Some(Transition::WentDown { since })
+            }
+            _ => {
+                self.state = HealthState::Degraded;
+                None
+            }
+        }
+    }
+
+    pub(crate) fn success(&mut self, at: SystemTime) -> Option<Transition> {
+        let since = self.since.take();
+        self.failures = 0;
+
+        match (self.state, since) {
+            (HealthState::Down, Some(since)) => {
+                self.state = HealthState::Recovered;
+                Some(Transition::Recovered {
+                    since,
+                    outage: at.duration_since(since).unwrap_or_default(),
+                })
+            }
+            _ => {
+                self.state = HealthState::Up;
+                None
+            }
+        }
+    }
+}
+
+/// `outage` as e.g. `2h 5m` or `40s`
+pub(crate) fn format_duration(outage: Duration) -> String {
+    let seconds = outage.as_secs();
+    let (days, hours, minutes) = (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60);
+
+    match (days, hours, minutes) {
+        (0, 0, 0) => format!("{}s", seconds),
+        (0, 0, minutes) => format!("{}m", minutes),
+        (0, hours, minutes) => format!("{}h {}m", hours, minutes),
+        (days, hours, _) => format!("{}d {}h", days, hours),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(seconds: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn down_after_threshold_then_recovered() {
+        let mut health = Health::new(3);
+
+        assert_eq!(health.failure(at(10)), None);
+        assert_eq!(health.state(), HealthState::Degraded);
+        assert_eq!(health.failure(at(20)), None);
+        assert_eq!(health.failures(), 2);
+
+        assert_eq!(
+            health.failure(at(30)),
+            Some(Transition::WentDown { since: at(10) })
+        );
+        assert_eq!(health.state(), HealthState::Down);
+        // Only one notification per outage
+        assert_eq!(health.failure(at(40)), None);
+        assert_eq!(health.state(), HealthState::Down);
+
+        assert_eq!(
+            health.success(at(130)),
+            Some(Transition::Recovered {
+                since: at(10),
+                outage: Duration::from_secs(120),
+            })
+        );
+        assert_eq!(health.state(), HealthState::Recovered);
+        assert_eq!(health.failures(), 0);
+
+        assert_eq!(health.success(at(140)), None);
+        assert_eq!(health.state(), HealthState::Up);
+    }
+
+    #[test]
+    fn degraded_recovers_silently() {
+        let mut health = Health::new(2);
+
+        assert_eq!(health.failure(at(10)), None);
+        assert_eq!(health.success(at(20)), None);
+        assert_eq!(health.state(), HealthState::Up);
+
+        // The count starts over after a success
+        assert_eq!(health.failure(at(30)), None);
+        assert_eq!(health.state(), HealthState::Degraded);
+        assert_eq!(
+            health.failure(at(40)),
+            Some(Transition::WentDown { since: at(30) })
+        );
+    }
+
+    #[test]
+    fn lowered_threshold_applies_to_next_failure() {
+        let mut health = Health::new(5);
+
+        health.failure(at(10));
+        health.failure(at(20));
+        health.set_down_after(2);
+
+        assert_eq!(
+            health.failure(at(30)),
+            Some(Transition::WentDown { since: at(10) })
+        );
+    }
+
+    #[test]
+    fn formats_durations() {
+        assert_eq!(format_duration(Duration::from_secs(40)), "40s");
+        assert_eq!(format_duration(Duration::from_secs(5 * 60 + 3)), "5m");
+        assert_eq!(
+            format_duration(Duration::from_secs(2 * 3600 + 5 * 60)),
+            "2h 5m"
+        );
+        assert_eq!(
+            format_duration(Duration::from_secs(3 * 86400 + 4 * 3600)),
+            "3d 4h"
+        );
+    }
+}
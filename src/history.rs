@@ -0,0 +1,644 @@
+use crate::{
+    config::Config,
+    diff::{ContentDiff, DiffHeader, OutputFormat},
+    extract::Extractor,
+};
+use anyhow::{bail, Context};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use structopt::StructOpt;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS contents (
+    hash TEXT PRIMARY KEY,
+    body BLOB NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS fetches (
+    id INTEGER PRIMARY KEY,
+    site TEXT NOT NULL,
+    url TEXT NOT NULL,
+    fetched_at INTEGER NOT NULL,
+    status INTEGER NOT NULL,
+    latency_ms INTEGER NOT NULL,
+    hash TEXT NOT NULL REFERENCES contents(hash)
+);
+
+CREATE INDEX IF NOT EXISTS fetches_site_fetched_at ON fetches(site, fetched_at);
+
+CREATE TABLE IF NOT EXISTS changes (
+    id INTEGER PRIMARY KEY,
+    fetch_id INTEGER NOT NULL REFERENCES fetches(id) ON DELETE CASCADE,
+    site TEXT NOT NULL,
+    detected_at INTEGER NOT NULL,
+    old_status INTEGER NOT NULL,
+    new_status INTEGER NOT NULL,
+    diff TEXT,
+    old_fetch_id INTEGER REFERENCES fetches(id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS changes_site_detected_at ON changes(site, detected_at);
+";
+
+/// Every fetch and every detected change, kept in a local SQLite database.
+///
+/// Response bodies are stored once per distinct sha256 hash, so a page that
+/// does not change does not grow the database.
+#[derive(Clone, Debug)]
+pub(crate) struct History {
+    conn: Arc<Mutex<Connection>>,
+}
+
+pub(crate) struct NewFetch<'a> {
+    pub(crate) site: &'a str,
+    pub(crate) url: &'a str,
+    pub(crate) fetched_at: i64,
+    pub(crate) status: u16,
+    pub(crate) latency: Duration,
+    pub(crate) body: &'a [u8],
+}
+
+pub(crate) struct NewChange<'a> {
+    pub(crate) fetch_id: i64,
+    /// The fetch the change was diffed against, which is not the previous
+    /// fetch after an outage
+    pub(crate) old_fetch_id: Option<i64>,
+    pub(crate) site: &'a str,
+    pub(crate) detected_at: i64,
+    pub(crate) old_status: u16,
+    pub(crate) new_status: u16,
+    pub(crate) diff: Option<&'a str>,
+}
+
+#[derive(Debug)]
+pub(crate) struct FetchRecord {
+    pub(crate) id: i64,
+    pub(crate) url: String,
+    pub(crate) fetched_at: i64,
+    pub(crate) status: u16,
+    pub(crate) latency_ms: i64,
+    pub(crate) hash: String,
+}
+
+#[derive(Debug)]
+pub(crate) struct ChangeRecord {
+    pub(crate) id: i64,
+    pub(crate) fetch_id: i64,
+    pub(crate) old_fetch_id: Option<i64>,
+    pub(crate) site: String,
+    pub(crate) detected_at: i64,
+    pub(crate) old_status: u16,
+    pub(crate) new_status: u16,
+    pub(crate) diff: Option<String>,
+}
+
+/// A recorded change with the bodies it was computed from
+#[derive(Debug)]
+pub(crate) struct ChangeContents {
+    pub(crate) change: ChangeRecord,
+    /// When the body before the change was fetched and the body, `None` once
+    /// it has been pruned
+    pub(crate) old: Option<(i64, Vec<u8>)>,
+    pub(crate) new: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub(crate) struct SiteStats {
+    pub(crate) site: String,
+    pub(crate) fetches: i64,
+    pub(crate) changes: i64,
+    pub(crate) versions: i64,
+    pub(crate) last_change: Option<i64>,
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) enum HistoryCommand {
+    /// List the most recent fetches of a site
+    Fetches {
+        site: String,
+
+        #[structopt(short, long, default_value = "20")]
+        limit: u32,
+    },
+    /// List the most recent changes, optionally for a single site
+    Changes {
+        site: Option<String>,
+
+        #[structopt(short, long, default_value = "20")]
+        limit: u32,
+
+        /// Also print the diff of every change
+        #[structopt(short, long)]
+        diff: bool,
+    },
+    /// Print the body a site had at a point in time
+    At {
+        site: String,
+
+        /// Local time, formatted as `2021-06-01`, `2021-06-01 14:30` or RFC 3339
+        time: String,
+    },
+    /// Show how often every site changed
+    Stats {
+        /// Only count the last N days
+        #[structopt(long)]
+        days: Option<u32>,
+    },
+    /// Recompute the diff of a recorded change from the stored contents
+    Diff {
+        id: i64,
+
+        /// Output format, `text`, `html` or `unified`
+        #[structopt(short, long, default_value = "text")]
+        format: OutputFormat,
+
+        /// Unchanged lines around every change in unified diffs, defaults to
+        /// the site's `context`
+        #[structopt(short = "U", long)]
+        context: Option<usize>,
+    },
+    /// Delete fetches and changes older than the given number of days
+    Prune { days: u32 },
+}
+
+impl History {
+    pub(crate) fn open(path: &Path) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open history {}", path.display()))?;
+
+        conn.execute_batch("PRAGMA foreign_keys = ON; PRAGMA journal_mode = WAL;")?;
+        conn.execute_batch(SCHEMA)
+            .context("Failed to create history tables")?;
+
+        Ok(History {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    pub(crate) async fn record_fetch(&self, fetch: NewFetch<'_>) -> anyhow::Result<i64> {
+        let hash = content_hash(fetch.body);
+        let site = fetch.site.to_string();
+        let url = fetch.url.to_string();
+        let body = fetch.body.to_vec();
+        let fetched_at = fetch.fetched_at;
+        let status = fetch.status;
+        let latency_ms = fetch.latency.as_millis() as i64;
+
+        self.blocking(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT OR IGNORE INTO contents (hash, body) VALUES (?1, ?2)",
+                params![hash, body],
+            )?;
+            tx.execute(
+                "INSERT INTO fetches (site, url, fetched_at, status, latency_ms, hash)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![site, url, fetched_at, status, latency_ms, hash],
+            )?;
+            let id = tx.last_insert_rowid();
+            tx.commit()?;
+            Ok(id)
+        })
+        .await
+    }
+
+    pub(crate) async fn record_change(&self, change: NewChange<'_>) -> anyhow::Result<i64> {
+        let fetch_id = change.fetch_id;
+        let old_fetch_id = change.old_fetch_id;
+        let site = change.site.to_string();
+        let detected_at = change.detected_at;
+        let old_status = change.old_status;
+        let new_status = change.new_status;
+        let diff = change.diff.map(|d| d.to_string());
+
+        // A baseline pruned during a long outage is left out, as if it had
+        // been deleted after the change
+        self.blocking(move |conn| {
+            conn.execute(
+                "INSERT INTO changes
+                 (fetch_id, site, detected_at, old_status, new_status, diff, old_fetch_id)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, (SELECT id FROM fetches WHERE id = ?7))",
+                params![
+                    fetch_id,
+                    site,
+                    detected_at,
+                    old_status,
+                    new_status,
+                    diff,
+                    old_fetch_id
+                ],
+            )?;
+            Ok(conn.last_insert_rowid())
+        })
+        .await
+    }
+
+    /// Delete everything older than `retention` but the last fetch of every
+    /// site, the baseline of its next change, and the bodies no fetch refers
+    /// to anymore, returning the number of deleted fetches
+    pub(crate) fn prune(&self, retention: Duration) -> anyhow::Result<usize> {
+        let cutoff = Local::now().timestamp() - retention.as_secs() as i64;
+
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let deleted = tx.execute(
+            "DELETE FROM fetches WHERE fetched_at < ?1
+             AND id NOT IN (SELECT MAX(id) FROM fetches GROUP BY site)",
+            params![cutoff],
+        )?;
+        tx.execute(
+            "DELETE FROM contents WHERE hash NOT IN (SELECT hash FROM fetches)",
+            [],
+        )?;
+        tx.commit()?;
+
+        Ok(deleted)
+    }
+
+    pub(crate) fn fetches(&self, site: &str, limit: u32) -> anyhow::Result<Vec<FetchRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, url, fetched_at, status, latency_ms, hash FROM fetches
+             WHERE site = ?1 ORDER BY fetched_at DESC, id DESC LIMIT ?2",
+        )?;
+
+        let records = stmt
+            .query_map(params![site, limit], fetch_record)?
+            .collect::<Result<_, _>>()?;
+
+        Ok(records)
+    }
+
+    pub(crate) fn changes(
+        &self,
+        site: Option<&str>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<ChangeRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, fetch_id, site, detected_at, old_status, new_status, diff, old_fetch_id
+             FROM changes
+             WHERE ?1 IS NULL OR site = ?1 ORDER BY detected_at DESC, id DESC LIMIT ?2",
+        )?;
+
+        let records = stmt
+            .query_map(params![site, limit], change_record)?
+            .collect::<Result<_, _>>()?;
+
+        Ok(records)
+    }
+
+    /// The last fetch of `site` at or before `time`, with its body
+    pub(crate) fn content_at(
+        &self,
+        site: &str,
+        time: i64,
+    ) -> anyhow::Result<Option<(FetchRecord, Vec<u8>)>> {
+        let conn = self.conn.lock().unwrap();
+
+        let fetch = conn
+            .query_row(
+                "SELECT id, url, fetched_at, status, latency_ms, hash FROM fetches
+                 WHERE site = ?1 AND fetched_at <= ?2 ORDER BY fetched_at DESC, id DESC LIMIT 1",
+                params![site, time],
+                fetch_record,
+            )
+            .optional()?;
+
+        let fetch = match fetch {
+            Some(fetch) => fetch,
+            None => return Ok(None),
+        };
+
+        let body = conn.query_row(
+            "SELECT body FROM contents WHERE hash = ?1",
+            params![fetch.hash],
+            |row| row.get(0),
+        )?;
+
+        Ok(Some((fetch, body)))
+    }
+
+    /// The change with the given id, with the body before and after it
+    pub(crate) fn change_contents(&self, id: i64) -> anyhow::Result<Option<ChangeContents>> {
+        let conn = self.conn.lock().unwrap();
+
+        let change = conn
+            .query_row(
+                "SELECT id, fetch_id, site, detected_at, old_status, new_status, diff, old_fetch_id
+                 FROM changes WHERE id = ?1",
+                params![id],
+                change_record,
+            )
+            .optional()?;
+
+        let change = match change {
+            Some(change) => change,
+            None => return Ok(None),
+        };
+
+        let new = conn.query_row(
+            "SELECT c.body FROM fetches f JOIN contents c ON c.hash = f.hash WHERE f.id = ?1",
+            params![change.fetch_id],
+            |row| row.get(0),
+        )?;
+
+        let old = match change.old_fetch_id {
+            Some(old_fetch_id) => conn
+                .query_row(
+                    "SELECT f.fetched_at, c.body FROM fetches f JOIN contents c ON c.hash = f.hash
+                     WHERE f.id = ?1",
+                    params![old_fetch_id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()?,
+            None => None,
+        };
+
+        Ok(Some(ChangeContents { change, old, new }))
+    }
+
+    pub(crate) fn stats(&self, since: Option<i64>) -> anyhow::Result<Vec<SiteStats>> {
+        let since = since.unwrap_or(i64::MIN);
+
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT f.site, COUNT(*), COUNT(DISTINCT f.hash),
+                 (SELECT COUNT(*) FROM changes c WHERE c.site = f.site AND c.detected_at >= ?1),
+                 (SELECT MAX(c.detected_at) FROM changes c WHERE c.site = f.site)
+             FROM fetches f WHERE f.fetched_at >= ?1 GROUP BY f.site ORDER BY f.site",
+        )?;
+
+        let stats = stmt
+            .query_map(params![since], |row| {
+                Ok(SiteStats {
+                    site: row.get(0)?,
+                    fetches: row.get(1)?,
+                    versions: row.get(2)?,
+                    changes: row.get(3)?,
+                    last_change: row.get(4)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+
+        Ok(stats)
+    }
+
+    async fn blocking<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = Arc::clone(&self.conn);
+
+        let res = tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap();
+            (f)(&mut conn)
+        })
+        .await??;
+
+        Ok(res)
+    }
+}
+
+impl HistoryCommand {
+    pub(crate) fn run(self, history: &History, config: &Path) -> anyhow::Result<()> {
+        match self {
+            HistoryCommand::Fetches { site, limit } => {
+                for fetch in history.fetches(&site, limit)? {
+                    println!(
+                        "#{} {} status {} in {}ms, content {}",
+                        fetch.id,
+                        format_time(fetch.fetched_at),
+                        fetch.status,
+                        fetch.latency_ms,
+                        &fetch.hash[..12],
+                    );
+                }
+            }
+            HistoryCommand::Changes { site, limit, diff } => {
+                for change in history.changes(site.as_deref(), limit)? {
+                    println!(
+                        "#{} {} {} (fetch #{}), status {} -> {}",
+                        change.id,
+                        format_time(change.detected_at),
+                        change.site,
+                        change.fetch_id,
+                        change.old_status,
+                        change.new_status,
+                    );
+
+                    if diff {
+                        if let Some(diff) = change.diff {
+                            println!("{}", diff);
+                        }
+                    }
+                }
+            }
+            HistoryCommand::At { site, time } => {
+                let time = parse_time(&time)?;
+
+                match history.content_at(&site, time)? {
+                    Some((fetch, body)) => {
+                        log::info!(
+                            "Fetch #{} of {} at {}, status {}",
+                            fetch.id,
+                            fetch.url,
+                            format_time(fetch.fetched_at),
+                            fetch.status
+                        );
+                        println!("{}", String::from_utf8_lossy(&body));
+                    }
+                    None => bail!("No fetch of '{}' at or before {}", site, format_time(time)),
+                }
+            }
+            HistoryCommand::Stats { days } => {
+                let since = days.map(|days| Local::now().timestamp() - i64::from(days) * 86_400);
+
+                for stats in history.stats(since)? {
+                    println!(
+                        "{}: {} fetches, {} changes, {} distinct versions, last change {}",
+                        stats.site,
+                        stats.fetches,
+                        stats.changes,
+                        stats.versions,
+                        stats
+                            .last_change
+                            .map(format_time)
+                            .unwrap_or_else(|| "never".to_string()),
+                    );
+                }
+            }
+            HistoryCommand::Diff {
+                id,
+                format,
+                context,
+            } => {
+                let ChangeContents { change, old, new } = history
+                    .change_contents(id)?
+                    .with_context(|| format!("No change #{}", id))?;
+                let (old_time, old) = old.with_context(|| {
+                    format!("The content before change #{} has been pruned", id)
+                })?;
+
+                let config = Config::load(config)?;
+                let site = config
+                    .sites
+                    .iter()
+                    .find(|site| site.name == change.site)
+                    .with_context(|| format!("Site '{}' is no longer configured", change.site))?;
+                let extractor = Extractor::new(site)?;
+
+                let diff = ContentDiff::new(
+                    &extractor.extract(&old),
+                    &extractor.extract(&new),
+                    extractor.mode(),
+                    site.inline,
+                );
+
+                match diff {
+                    Some(diff) => {
+                        let header = DiffHeader {
+                            site: &change.site,
+                            old_time,
+                            new_time: change.detected_at,
+                            context: context.unwrap_or(site.context),
+                        };
+                        print!("{}", diff.render(format, &header));
+                    }
+                    None => log::info!("No content change with the current site config"),
+                }
+            }
+            HistoryCommand::Prune { days } => {
+                let deleted = history.prune(Duration::from_secs(u64::from(days) * 86_400))?;
+                println!("Deleted {} fetches", deleted);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn content_hash(body: &[u8]) -> String {
+    hex::encode(Sha256::digest(body))
+}
+
+fn fetch_record(row: &rusqlite::Row<'_>) -> rusqlite::Result<FetchRecord> {
+    Ok(FetchRecord {
+        id: row.get(0)?,
+        url: row.get(1)?,
+        fetched_at: row.get(2)?,
+        status: row.get(3)?,
+        latency_ms: row.get(4)?,
+        hash: row.get(5)?,
+    })
+}
+
+fn change_record(row: &rusqlite::Row<'_>) -> rusqlite::Result<ChangeRecord> {
+    Ok(ChangeRecord {
+        id: row.get(0)?,
+        fetch_id: row.get(1)?,
+        site: row.get(2)?,
+        detected_at: row.get(3)?,
+        old_status: row.get(4)?,
+        new_status: row.get(5)?,
+        diff: row.get(6)?,
+        old_fetch_id: row.get(7)?,
+    })
+}
+
+fn format_time(timestamp: i64) -> String {
+    match Local.timestamp_opt(timestamp, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+fn parse_time(s: &str) -> anyhow::Result<i64> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.timestamp());
+    }
+
+    let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M"))
+        .or_else(|_| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d").map(|d| d.and_hms_opt(23, 59, 59).unwrap())
+        });
+
+    match naive {
+        Ok(naive) => match Local.from_local_datetime(&naive).earliest() {
+            Some(time) => Ok(time.timestamp()),
+            None => bail!("'{}' does not exist in the local time zone", s),
+        },
+        Err(_) => bail!("Invalid time '{}'", s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn fetch(history: &History, fetched_at: i64, body: &[u8]) -> i64 {
+        history
+            .record_fetch(NewFetch {
+                site: "site",
+                url: "http://example.com/",
+                fetched_at,
+                status: 200,
+                latency: Duration::from_millis(10),
+                body,
+            })
+            .await
+            .unwrap()
+    }
+
+    async fn change(history: &History, fetch_id: i64, old_fetch_id: i64) -> i64 {
+        history
+            .record_change(NewChange {
+                fetch_id,
+                old_fetch_id: Some(old_fetch_id),
+                site: "site",
+                detected_at: Local::now().timestamp(),
+                old_status: 200,
+                new_status: 200,
+                diff: Some("changed"),
+            })
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn prune_keeps_the_baseline() {
+        let history = History::open(Path::new(":memory:")).unwrap();
+        let old = Local::now().timestamp() - 10 * 86_400;
+
+        let first = fetch(&history, old, b"a").await;
+        let baseline = fetch(&history, old + 1, b"b").await;
+
+        assert_eq!(history.prune(Duration::from_secs(86_400)).unwrap(), 1);
+        let fetches = history.fetches("site", 10).unwrap();
+        assert_eq!(fetches.len(), 1);
+        assert_eq!(fetches[0].id, baseline);
+
+        let new = fetch(&history, Local::now().timestamp(), b"c").await;
+        let id = change(&history, new, baseline).await;
+
+        let contents = history.change_contents(id).unwrap().unwrap();
+        assert_eq!(contents.change.old_fetch_id, Some(baseline));
+        assert_eq!(contents.old.unwrap().1, b"b");
+        assert_eq!(contents.new, b"c");
+
+        // A baseline that is gone no longer fails the change
+        let newer = fetch(&history, Local::now().timestamp(), b"d").await;
+        let id = change(&history, newer, first).await;
+        let contents = history.change_contents(id).unwrap().unwrap();
+        assert_eq!(contents.change.old_fetch_id, None);
+        assert!(contents.old.is_none());
+    }
+}
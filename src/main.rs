@@ -1,192 +1,220 @@
-use bytes::Bytes;
-use prettydiff::{basic::DiffOp, diff_lines};
-use reqwest::{Client, StatusCode};
-use std::time::Duration;
-
-static NZZ_HREF: &'static str = "https://nzz.ch/";
-static NAU_HREF: &'static str = "https://www.nau.ch/";
-static ZWM_HREF: &'static str = "https://20min.ch";
-static ADM_HREF: &'static str = "https://www.admin.ch/gov/de/start/dokumentation/medienmitteilungen.html?dyn_startDate=01.01.2020&dyn_organization=1";
-
-static SITES: &'static [(&'static str, &'static str)] = &[
-    ("Neue Züricher Zeitung", NZZ_HREF),
-    ("NAU", NAU_HREF),
-    ("20 Minuten", ZWM_HREF),
-    ("Admin.ch News", ADM_HREF),
-];
-
-#[derive(Debug)]
-enum SiteMessage {
-    Check,
+use anyhow::Context;
+use reqwest::Client;
+use std::{path::PathBuf, time::Duration};
+use structopt::StructOpt;
+use tokio::signal::unix::{signal, SignalKind};
+
+mod auth;
+mod config;
+mod diff;
+mod dom;
+mod error;
+mod extract;
+mod fetch;
+mod health;
+mod history;
+mod normalize;
+mod notify;
+mod request;
+mod robots;
+mod scheduler;
+mod site;
+mod store;
+mod supervisor;
+mod template;
+#[cfg(test)]
+mod test_util;
+
+use auth::{Auth, Secrets};
+use config::Config;
+use extract::Extractor;
+use history::{History, HistoryCommand};
+use request::{SiteRequest, DEFAULT_USER_AGENT};
+use scheduler::Scheduler;
+use store::SnapshotStore;
+use supervisor::Supervisor;
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Watch web sites and get notified when they change")]
+struct Opt {
+    /// Path to the TOML file declaring the sites to watch, reloaded on SIGHUP
+    #[structopt(
+        short,
+        long,
+        env = "SITE_CHECKER_CONFIG",
+        default_value = "sites.toml",
+        parse(from_os_str)
+    )]
+    config: PathBuf,
+
+    /// Directory holding the last result of every site
+    #[structopt(
+        long,
+        env = "SITE_CHECKER_SNAPSHOTS",
+        default_value = "snapshots",
+        parse(from_os_str)
+    )]
+    snapshot_dir: PathBuf,
+
+    /// SQLite database recording every fetch and change
+    #[structopt(
+        long,
+        env = "SITE_CHECKER_HISTORY",
+        default_value = "history.sqlite3",
+        parse(from_os_str)
+    )]
+    history: PathBuf,
+
+    /// TOML file with the credentials of sites needing a login
+    #[structopt(long, env = "SITE_CHECKER_SECRETS", parse(from_os_str))]
+    secrets: Option<PathBuf>,
+
+    /// Delete history entries older than this many days
+    #[structopt(long, env = "SITE_CHECKER_RETENTION_DAYS")]
+    retention_days: Option<u32>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
-#[derive(Debug)]
-struct SiteState {
-    name: String,
-    href: String,
-    client: Client,
-    result: Option<SiteResult>,
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Watch the configured sites, the default when no command is given
+    Run,
+    /// Query the recorded fetches and changes
+    History(HistoryCommand),
+    /// Fetch a site once and show what its normalizers and ignore rules remove
+    DryRun { site: String },
 }
 
-#[derive(Debug)]
-struct SiteResult {
-    status: StatusCode,
-    bytes: Bytes,
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    if std::env::var("RUST_LOG").is_err() {
+        std::env::set_var("RUST_LOG", "info");
+    }
+    env_logger::init();
+
+    let opt = Opt::from_args();
+
+    match opt.command {
+        Some(Command::History(command)) => command.run(&History::open(&opt.history)?, &opt.config),
+        Some(Command::DryRun { ref site }) => dry_run(&opt, site).await,
+        Some(Command::Run) | None => run(opt).await,
+    }
 }
 
-struct SiteResultDiff {
-    status: Option<StatusCode>,
-    diff: Option<String>,
+fn client() -> anyhow::Result<Client> {
+    Ok(Client::builder().user_agent(DEFAULT_USER_AGENT).build()?)
 }
 
-impl SiteState {
-    async fn check(&mut self) -> anyhow::Result<()> {
-        log::info!("Checking {}", self.name);
-        let response = self
-            .client
-            .get(&self.href)
-            .header("Accept", "text/html")
-            .send()
-            .await?;
-
-        let status = response.status();
-        let bytes = response.bytes().await?;
-
-        let new_result = SiteResult { status, bytes };
-
-        let prev = self.result.take();
-
-        let diff = prev.as_ref().map(|result| result.diff(&new_result));
-
-        if let Some(diff) = diff {
-            if diff.is_different() {
-                let title = format!("{} Updated", self.name);
-                let description = if let Some(status) = diff.status {
-                    if let Some(diff) = diff.diff {
-                        format!("New status '{}' and site content changed\n{}", status, diff,)
-                    } else {
-                        format!("New status '{}'", status)
-                    }
-                } else if let Some(diff) = diff.diff {
-                    format!("Site content changed\n{}", diff)
-                } else {
-                    format!("Site content changed")
-                };
-
-                log::info!("{}", title);
-                log::info!("{}", description);
-                tokio::process::Command::new("notify-send")
-                    .args(&["-i", "appointment", &title])
-                    .spawn()?
-                    .wait()
-                    .await?;
-            }
-        } else {
-            log::info!(
-                "First check for {}, status: {}",
-                self.name,
-                new_result.status
-            );
-        }
+async fn run(opt: Opt) -> anyhow::Result<()> {
+    let config = Config::load(&opt.config)?;
+    let history = History::open(&opt.history)?;
 
-        self.result = Some(new_result);
-        Ok(())
-    }
+    let client = client()?;
 
-    async fn handle_message(&mut self, message: SiteMessage) -> anyhow::Result<()> {
-        match message {
-            SiteMessage::Check => {
-                self.check().await?;
-            }
-        }
+    let store = SnapshotStore::new(&opt.snapshot_dir)?;
 
-        Ok(())
+    if let Some(days) = opt.retention_days {
+        spawn_pruner(
+            history.clone(),
+            Duration::from_secs(u64::from(days) * 86_400),
+        );
     }
-}
 
-impl SiteResult {
-    fn diff(&self, rhs: &SiteResult) -> SiteResultDiff {
-        let status = if self.status != rhs.status {
-            Some(rhs.status.clone())
-        } else {
-            None
-        };
-
-        let old = String::from_utf8_lossy(&self.bytes);
-        let new = String::from_utf8_lossy(&rhs.bytes);
-
-        let changeset = diff_lines(&old, &new);
-
-        let diff: Vec<DiffOp<'_, &str>> = changeset
-            .diff()
-            .into_iter()
-            .filter(|op| match op {
-                DiffOp::Equal(_) => false,
-                _ => true,
-            })
-            .collect();
-
-        let diff = if !diff.is_empty() {
-            Some(render_diff(&diff))
-        } else {
-            None
-        };
-
-        SiteResultDiff { status, diff }
-    }
-}
+    let secrets = Secrets::new(opt.secrets.clone());
 
-impl SiteResultDiff {
-    fn is_different(&self) -> bool {
-        self.status.is_some() || self.diff.is_some()
-    }
-}
+    let mut supervisor = Supervisor::new(client, store, history, secrets);
+    supervisor.apply(config).await?;
 
-fn render_diff(ops: &[DiffOp<'_, &str>]) -> String {
-    ops.iter().fold(String::new(), |acc, op| match op {
-        DiffOp::Equal(_) => acc,
-        DiffOp::Insert(a) => acc + "Added:\n" + &a.join("\n") + "\n\n",
-        DiffOp::Remove(a) => acc + "Removed:\n" + &a.join("\n") + "\n\n",
-        DiffOp::Replace(a, b) => {
-            acc + "Replaced:\n" + &a.join("\n") + "\nwith\n" + &b.join("\n") + "\n\n"
-        }
-    })
-}
+    let mut hangup = signal(SignalKind::hangup())?;
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    if std::env::var("RUST_LOG").is_err() {
-        std::env::set_var("RUST_LOG", "info");
+    loop {
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => {
+                res?;
+                break;
+            }
+            _ = hangup.recv() => {
+                log::info!("Reloading {}", opt.config.display());
+                match Config::load(&opt.config) {
+                    Ok(config) => {
+                        if let Err(e) = supervisor.apply(config).await {
+                            log::error!("Failed to apply new config: {:?}", e);
+                        }
+                    }
+                    Err(e) => log::error!("Keeping current config: {:?}", e),
+                }
+            }
+        }
     }
-    env_logger::init();
 
-    let client = Client::builder().user_agent("Site Checker").build()?;
+    supervisor.close().await;
 
-    let mut root_handle = tokio_actors::root();
+    Ok(())
+}
 
-    for (name, href) in SITES {
-        let name = name.to_string();
-        let href = href.to_string();
-        let client = client.clone();
+async fn dry_run(opt: &Opt, name: &str) -> anyhow::Result<()> {
+    let config = Config::load(&opt.config)?;
+    let site = config
+        .sites
+        .iter()
+        .find(|site| site.name == name)
+        .with_context(|| format!("No site '{}' in {}", name, opt.config.display()))?;
+
+    let extractor = Extractor::new(site)?;
+
+    let client = client()?;
+    let request = SiteRequest::new(site)?;
+    let auth = Auth::new(site, &Secrets::new(opt.secrets.clone()))?;
+
+    let scheduler = Scheduler::new(client.clone());
+    scheduler.reconfigure(config.politeness.clone());
+
+    let (response, _permit) = auth
+        .send(&scheduler, |session| {
+            request.build(&client, &site.url, session)
+        })
+        .await?;
+    log::info!("Fetched {}, status: {}", site.url, response.status());
+    let bytes = response.bytes().await?;
+
+    let selected = extractor.select(&bytes);
+    let matches = extractor.normalizer.matches(&selected);
+
+    for m in &matches {
+        println!(
+            "[{}] line {}: {:?} -> {:?}",
+            m.rule, m.line, m.matched, m.replaced
+        );
+    }
 
-        let state = SiteState {
-            name,
-            href,
-            client,
-            result: None,
-        };
+    let normalized = extractor.normalizer.apply(&selected);
+    println!(
+        "{} matches, {} of {} bytes left after normalization",
+        matches.len(),
+        normalized.len(),
+        selected.len()
+    );
 
-        let handle = root_handle
-            .spawn_child(state, move |state, msg, _| {
-                Box::pin(async move { state.handle_message(msg).await })
-            })
-            .await?;
-        handle.every(Duration::from_secs(30 * 60), || SiteMessage::Check);
-    }
+    Ok(())
+}
 
-    tokio::signal::ctrl_c().await?;
+/// Apply the history retention once an hour
+fn spawn_pruner(history: History, retention: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
 
-    root_handle.close().await;
+        loop {
+            interval.tick().await;
 
-    Ok(())
+            let history = history.clone();
+            match tokio::task::spawn_blocking(move || history.prune(retention)).await {
+                Ok(Ok(deleted)) if deleted > 0 => log::info!("Pruned {} old fetches", deleted),
+                Ok(Ok(_)) => (),
+                Ok(Err(e)) => log::error!("Failed to prune history: {:?}", e),
+                Err(e) => log::error!("History pruning panicked: {}", e),
+            }
+        }
+    });
 }
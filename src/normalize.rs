@@ -0,0 +1,230 @@
+use crate::config::{IgnoreRule, SiteConfig};
+use anyhow::{bail, Context};
+use regex::Regex;
+use std::borrow::Cow;
+
+/// Built-in rules for content that changes on every request, by name
+static BUILTINS: &[(&str, &[(&str, &str)])] = &[
+    (
+        "timestamps",
+        &[
+            (
+                r"\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}(:\d{2}(\.\d+)?)?(Z|[+-]\d{2}:?\d{2})?",
+                "",
+            ),
+            (r"\b\d{1,2}\.\d{1,2}\.\d{2,4}\b", ""),
+            (r"\b\d{1,2}:\d{2}(:\d{2})?\b", ""),
+        ],
+    ),
+    ("nonces", &[(r#"\s(nonce|integrity)="[^"]*""#, "")]),
+    (
+        "cache-busting",
+        &[(
+            r#"(\.(css|js|mjs|png|jpe?g|gif|svg|webp|avif|ico|woff2?))\?[^"'\s>]*"#,
+            "$1",
+        )],
+    ),
+    (
+        "session-ids",
+        &[(
+            r"(?i)\b(jsessionid|phpsessid|aspsessionid\w*|sessionid|sid)=[\w.-]+",
+            "$1=",
+        )],
+    ),
+    (
+        "csrf",
+        &[(
+            r#"(?i)((csrf|xsrf|authenticity)[\w-]*"?(\s+[\w-]+="[^"]*")*\s+(value|content)=")[^"]*""#,
+            r#"$1""#,
+        )],
+    ),
+];
+
+/// Regex rewrites applied to both the old and the new content before they are
+/// diffed, so volatile parts of a page do not show up as changes
+#[derive(Debug)]
+pub(crate) struct Normalizer {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    name: String,
+    regex: Regex,
+    replace: String,
+}
+
+/// A match of one rule, as reported by `Normalizer::matches`
+pub(crate) struct RuleMatch<'a> {
+    pub(crate) rule: &'a str,
+    pub(crate) line: usize,
+    pub(crate) matched: &'a str,
+    pub(crate) replaced: String,
+}
+
+impl Normalizer {
+    pub(crate) fn new(site: &SiteConfig) -> anyhow::Result<Self> {
+        let mut rules = Vec::new();
+
+        for name in &site.normalize {
+            let builtin = BUILTINS.iter().find(|(builtin, _)| builtin == name);
+
+            let patterns = match builtin {
+                Some((_, patterns)) => patterns,
+                None => bail!(
+                    "Unknown normalizer '{}', expected one of {}",
+                    name,
+                    BUILTINS
+                        .iter()
+                        .map(|(name, _)| *name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            };
+
+            for (pattern, replace) in patterns.iter() {
+                rules.push(Rule {
+                    name: name.clone(),
+                    regex: Regex::new(pattern).expect("Built-in patterns are valid"),
+                    replace: replace.to_string(),
+                });
+            }
+        }
+
+        for rule in &site.ignore {
+            let (pattern, replace) = match rule {
+                IgnoreRule::Pattern(pattern) => (pattern, ""),
+                IgnoreRule::Replace { pattern, replace } => (pattern, replace.as_str()),
+            };
+
+            rules.push(Rule {
+                name: pattern.clone(),
+                regex: Regex::new(pattern)
+                    .with_context(|| format!("Invalid ignore pattern '{}'", pattern))?,
+                replace: replace.to_string(),
+            });
+        }
+
+        Ok(Normalizer { rules })
+    }
+
+    pub(crate) fn apply<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        self.rules.iter().fold(Cow::Borrowed(text), |text, rule| {
+            match rule.regex.replace_all(&text, rule.replace.as_str()) {
+                Cow::Borrowed(_) => text,
+                Cow::Owned(replaced) => Cow::Owned(replaced),
+            }
+        })
+    }
+
+    /// Every match of every rule in `text`, without applying the rules to
+    /// each other's output
+    pub(crate) fn matches<'a>(&'a self, text: &'a str) -> Vec<RuleMatch<'a>> {
+        let mut matches = Vec::new();
+
+        for rule in &self.rules {
+            for captures in rule.regex.captures_iter(text) {
+                let matched = captures.get(0).expect("Group 0 always matches");
+
+                let mut replaced = String::new();
+                captures.expand(&rule.replace, &mut replaced);
+
+                matches.push(RuleMatch {
+                    rule: &rule.name,
+                    line: text[..matched.start()].matches('\n').count() + 1,
+                    matched: matched.as_str(),
+                    replaced,
+                });
+            }
+        }
+
+        matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::site;
+
+    fn normalizer(toml: &str) -> Normalizer {
+        Normalizer::new(&site("http://example.com/", toml)).unwrap()
+    }
+
+    #[test]
+    fn builtins() {
+        let normalizer = normalizer(
+            "normalize = [\"timestamps\", \"nonces\", \"cache-busting\", \"session-ids\", \"csrf\"]",
+        );
+
+        assert_eq!(
+            normalizer.apply("Updated 2021-06-01T14:30:00+02:00, 1.6.2021 at 14:30."),
+            "Updated ,  at ."
+        );
+        assert_eq!(
+            normalizer.apply("<script nonce=\"abc\" integrity=\"sha384-x\" src=\"a.js?v=123\">"),
+            "<script src=\"a.js\">"
+        );
+        assert_eq!(
+            normalizer.apply("<a href=\"/cart;jsessionid=A1B2.n1?PHPSESSID=f00\">"),
+            "<a href=\"/cart;jsessionid=?PHPSESSID=\">"
+        );
+        assert_eq!(
+            normalizer.apply(
+                "<input name=\"csrf_token\" type=\"hidden\" value=\"s3cr3t\">\
+                 <meta name=\"csrf-token\" content=\"t0k3n\">"
+            ),
+            "<input name=\"csrf_token\" type=\"hidden\" value=\"\">\
+             <meta name=\"csrf-token\" content=\"\">"
+        );
+    }
+
+    #[test]
+    fn ignore_rules_remove_and_replace() {
+        let normalizer = normalizer(
+            "ignore = [\n\
+             { pattern = 'Visitors: \\d+', replace = 'Visitors: n' },\n\
+             '(?s)<!-- ad -->.*?<!-- /ad -->',\n\
+             ]",
+        );
+
+        assert_eq!(
+            normalizer.apply("<p>Visitors: 1234</p><!-- ad -->\n<img>\n<!-- /ad --><p>News</p>"),
+            "<p>Visitors: n</p><p>News</p>"
+        );
+        assert!(matches!(normalizer.apply("<p>News</p>"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn matches_report_rule_and_line() {
+        let normalizer = normalizer("normalize = [\"timestamps\"]\nignore = ['id=\"\\w+\"']");
+        let matches = normalizer.matches("<p id=\"x1\">\nat 12:30\n</p>");
+
+        let found = matches
+            .iter()
+            .map(|m| (m.rule, m.line, m.matched, m.replaced.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            [
+                ("timestamps", 2, "12:30", ""),
+                ("id=\"\\w+\"", 1, "id=\"x1\"", ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_rules() {
+        let unknown = Normalizer::new(&site("http://example.com/", "normalize = [\"dates\"]"));
+        assert!(unknown
+            .unwrap_err()
+            .to_string()
+            .starts_with("Unknown normalizer 'dates', expected one of timestamps"));
+
+        let invalid = Normalizer::new(&site("http://example.com/", "ignore = ['(']"));
+        assert_eq!(
+            invalid.unwrap_err().to_string(),
+            "Invalid ignore pattern '('"
+        );
+    }
+}
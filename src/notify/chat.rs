@@ -0,0 +1,118 @@
+//! Helpers shared by the chat notifiers
+
+use crate::{diff::OutputFormat, notify::ChangeEvent};
+
+/// Name of the attachment holding the full diff
+pub(super) const DIFF_FILE: &str = "diff.txt";
+
+/// The diff as shown in chat messages, a unified diff with the `diff`
+/// highlighting Discord and Mattermost offer for it
+pub(super) fn diff(event: &ChangeEvent) -> Option<String> {
+    event.render(OutputFormat::Unified)
+}
+
+/// Cut `text` on a line boundary to at most `limit` characters, the flag is
+/// set if anything was cut off
+pub(super) fn cut(text: &str, limit: usize) -> (String, bool) {
+    if text.chars().count() <= limit {
+        return (text.to_string(), false);
+    }
+
+    let mut cut = String::new();
+    let mut len = 0;
+
+    for line in text.split_inclusive('\n') {
+        let line_len = line.chars().count();
+
+        if len + line_len > limit {
+            break;
+        }
+
+        cut.push_str(line);
+        len += line_len;
+    }
+
+    // A single line longer than the limit
+    if cut.is_empty() {
+        cut = text.chars().take(limit).collect();
+    }
+
+    (cut, true)
+}
+
+/// Code fence around `text`, which must not end the block early, with an
+/// optional `language` hint for clients highlighting it
+pub(super) fn code_block(language: &str, text: &str) -> String {
+    format!(
+        "```{}\n{}\n```",
+        language,
+        text.trim_end().replace("```", "`\u{200b}``")
+    )
+}
+
+/// The text of `digest` in a code block of at most `limit` characters
+pub(super) fn digest_block(text: &str, limit: usize) -> String {
+    // Room for the code fence and the ellipsis
+    let (text, cut) = cut(text, limit - 20);
+
+    format!(
+        "```\n{}{}\n```",
+        text.trim_end().replace("```", "`\u{200b}``"),
+        if cut { "\n…" } else { "" }
+    )
+}
+
+/// The `link` template with the ids of `event` filled in
+pub(super) fn link(template: Option<&str>, event: &ChangeEvent) -> Option<String> {
+    let template = template?;
+
+    let id = |id: Option<i64>| id.map(|id| id.to_string()).unwrap_or_default();
+
+    Some(
+        template
+            .replace("{change}", &id(event.change_id))
+            .replace("{snapshot}", &id(event.new_snapshot))
+            .replace("{site}", &event.site),
+    )
+}
+
+/// Where to find the full diff without a link
+pub(super) fn history_hint(event: &ChangeEvent) -> String {
+    match event.change_id {
+        Some(id) => format!("site-diff-checker_rs history diff {}", id),
+        None => "site-diff-checker_rs history changes --diff".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cut_on_line_boundary() {
+        assert_eq!(cut("short\n", 10), ("short\n".to_string(), false));
+        assert_eq!(
+            cut("first\nsecond\nthird\n", 14),
+            ("first\nsecond\n".to_string(), true)
+        );
+        // Counted in characters, not bytes
+        assert_eq!(cut("äöü\nxyz", 7), ("äöü\nxyz".to_string(), false));
+    }
+
+    #[test]
+    fn cut_long_single_line() {
+        assert_eq!(cut("abcdefgh\nij", 5), ("abcde".to_string(), true));
+    }
+
+    #[test]
+    fn code_blocks() {
+        assert_eq!(code_block("diff", "-a\n+b\n"), "```diff\n-a\n+b\n```");
+        assert_eq!(code_block("", "a ``` b"), "```\na `\u{200b}`` b\n```");
+
+        let text = (0..50).map(|i| format!("line {}\n", i)).collect::<String>();
+        let block = digest_block(&text, 60);
+        assert!(block.chars().count() <= 60);
+        assert!(block.starts_with("```\nline 0\n"));
+        assert!(block.ends_with("\n…\n```"));
+    }
+}
@@ -0,0 +1,146 @@
+use crate::{
+    config::DesktopConfig,
+    diff::{escape, OutputFormat},
+    notify::{digest::Digest, ChangeEvent, EventKind, Notifier},
+};
+use async_trait::async_trait;
+use notify_rust::{Notification, Urgency};
+use std::time::Duration;
+
+/// Most lines and characters of the diff shown in a notification
+const SUMMARY_LINES: usize = 8;
+const SUMMARY_LEN: usize = 400;
+
+/// Changed lines or elements from which a change is shown with normal and with
+/// critical urgency, smaller changes have low urgency. Down sites are critical,
+/// recoveries normal.
+const NORMAL_SIZE: usize = 3;
+const CRITICAL_SIZE: usize = 20;
+
+/// How long the "Open" action of a notification is listened for
+const ACTION_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Shows changes as freedesktop notifications over D-Bus, with the start of
+/// the diff and an action opening the site in the browser
+#[derive(Debug)]
+pub(crate) struct Desktop {
+    icon: String,
+}
+
+impl Desktop {
+    pub(crate) fn new(config: &DesktopConfig) -> Self {
+        Desktop {
+            icon: config.icon.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for Desktop {
+    async fn notify(&self, event: &ChangeEvent) -> anyhow::Result<()> {
+        let body = plain_body(summary(event)).await?;
+
+        let handle = Notification::new()
+            .appname("Site Checker")
+            .summary(&event.title)
+            .body(&body)
+            .icon(&self.icon)
+            .urgency(urgency(event))
+            .action("default", "Open")
+            .show_async()
+            .await?;
+
+        let url = event.url.clone();
+
+        tokio::spawn(async move {
+            let action = handle.wait_for_action_async(|response| {
+                if response.is_default_action() {
+                    if let Err(e) = std::process::Command::new("xdg-open").arg(&url).spawn() {
+                        log::error!("Failed to open {}: {:?}", url, e);
+                    }
+                }
+            });
+
+            // Critical notifications stay until they are closed
+            let _ = tokio::time::timeout(ACTION_TIMEOUT, action).await;
+        });
+
+        Ok(())
+    }
+
+    async fn notify_digest(&self, digest: &Digest) -> anyhow::Result<()> {
+        Notification::new()
+            .appname("Site Checker")
+            .summary(&digest.title())
+            .body(&plain_body(digest.overview()).await?)
+            .icon(&self.icon)
+            .urgency(Urgency::Low)
+            .show_async()
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Escape `body` if the server supports markup, which would swallow the tags
+/// of HTML diffs
+async fn plain_body(body: String) -> anyhow::Result<String> {
+    let markup = tokio::task::spawn_blocking(notify_rust::get_capabilities)
+        .await??
+        .iter()
+        .any(|capability| capability == "body-markup");
+
+    Ok(if markup { escape(&body) } else { body })
+}
+
+/// The new status and the first lines of the diff, or of the templated body
+fn summary(event: &ChangeEvent) -> String {
+    let mut lines = Vec::new();
+
+    if let Some(body) = &event.body {
+        lines.extend(body.lines().map(str::to_string));
+    } else {
+        if let Some(status) = event.status_change() {
+            lines.push(format!("New status '{}'", status));
+        }
+
+        if let Some(diff) = event.render(OutputFormat::Text) {
+            lines.extend(
+                diff.lines()
+                    .filter(|line| !line.trim().is_empty())
+                    .map(str::to_string),
+            );
+        }
+    }
+
+    let mut summary = lines
+        .iter()
+        .take(SUMMARY_LINES)
+        .cloned()
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    if let Some((idx, _)) = summary.char_indices().nth(SUMMARY_LEN) {
+        summary.truncate(idx);
+        summary.push('…');
+    } else if lines.len() > SUMMARY_LINES {
+        summary.push_str("\n…");
+    }
+
+    summary
+}
+
+fn urgency(event: &ChangeEvent) -> Urgency {
+    let size = event.changes.as_ref().map_or(0, |changes| changes.size());
+
+    if event.kind == EventKind::Down || event.new_status >= 400 || size >= CRITICAL_SIZE {
+        Urgency::Critical
+    } else if event.kind == EventKind::Recovered
+        || event.old_status != event.new_status
+        || size >= NORMAL_SIZE
+    {
+        Urgency::Normal
+    } else {
+        Urgency::Low
+    }
+}
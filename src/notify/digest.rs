@@ -0,0 +1,247 @@
+use crate::{
+    config::{DigestConfig, DigestWindow},
+    diff::{escape, OutputFormat},
+    notify::{ChangeEvent, EventKind},
+};
+use chrono::{DateTime, Duration as ChronoDuration, Local, TimeZone, Timelike, Utc};
+use serde::Serialize;
+use std::{sync::Arc, time::Duration};
+
+/// Most lines of the diffs shown per site
+const DIGEST_LINES: usize = 10;
+
+/// The changes of all sites during one digest window
+#[derive(Debug, Serialize)]
+pub(crate) struct Digest {
+    pub(crate) start: DateTime<Utc>,
+    pub(crate) end: DateTime<Utc>,
+    pub(crate) sites: Vec<DigestSite>,
+}
+
+/// The changes of one site during a digest window
+#[derive(Debug, Serialize)]
+pub(crate) struct DigestSite {
+    pub(crate) site: String,
+    pub(crate) url: String,
+    pub(crate) changes: usize,
+    /// Status before the first and after the last change
+    pub(crate) old_status: u16,
+    pub(crate) new_status: u16,
+    /// The first lines of the text diffs of all changes
+    pub(crate) diff: String,
+    /// History ids of the changes, for `history diff`
+    pub(crate) change_ids: Vec<i64>,
+}
+
+impl Digest {
+    /// Group `events` by site, in the order the sites first changed
+    pub(crate) fn new(start: DateTime<Utc>, events: &[Arc<ChangeEvent>]) -> Self {
+        let mut sites: Vec<(DigestSite, Vec<String>)> = Vec::new();
+
+        for event in events {
+            let idx = match sites.iter().position(|(site, _)| site.site == event.site) {
+                Some(idx) => idx,
+                None => {
+                    sites.push((
+                        DigestSite {
+                            site: event.site.clone(),
+                            url: event.url.clone(),
+                            changes: 0,
+                            old_status: event.old_status,
+                            new_status: event.old_status,
+                            diff: String::new(),
+                            change_ids: Vec::new(),
+                        },
+                        Vec::new(),
+                    ));
+                    sites.len() - 1
+                }
+            };
+
+            let (site, lines) = &mut sites[idx];
+            site.url = event.url.clone();
+            site.changes += 1;
+            site.change_ids.extend(event.change_id);
+
+            // The status of a down site is no result to compare with
+            if event.kind != EventKind::Down {
+                site.new_status = event.new_status;
+            }
+
+            if let Some(body) = &event.body {
+                lines.extend(body.lines().map(str::to_string));
+            } else if let Some(status) = event.status_change() {
+                lines.push(format!("New status '{}'", status));
+            }
+            if let Some(diff) = event
+                .render(OutputFormat::Text)
+                .filter(|_| event.body.is_none())
+            {
+                lines.extend(
+                    diff.lines()
+                        .filter(|line| !line.trim().is_empty())
+                        .map(str::to_string),
+                );
+            }
+        }
+
+        let sites = sites
+            .into_iter()
+            .map(|(mut site, lines)| {
+                site.diff = condense(&lines);
+                site
+            })
+            .collect();
+
+        Digest {
+            start,
+            end: Utc::now(),
+            sites,
+        }
+    }
+
+    pub(crate) fn changes(&self) -> usize {
+        self.sites.iter().map(|site| site.changes).sum()
+    }
+
+    pub(crate) fn title(&self) -> String {
+        format!(
+            "Digest: {} {} on {} {}",
+            self.changes(),
+            plural(self.changes(), "change"),
+            self.sites.len(),
+            plural(self.sites.len(), "site")
+        )
+    }
+
+    /// The covered time span in local time
+    pub(crate) fn period(&self) -> String {
+        format!(
+            "{} - {}",
+            self.start.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+            self.end.with_timezone(&Local).format("%Y-%m-%d %H:%M")
+        )
+    }
+
+    /// One line per site with its number of changes
+    pub(crate) fn overview(&self) -> String {
+        self.sites
+            .iter()
+            .map(|site| site.headline())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub(crate) fn render_text(&self) -> String {
+        let mut text = format!("{}\n", self.period());
+
+        for site in &self.sites {
+            text.push_str(&format!("\n{}\n{}\n", site.headline(), site.url));
+            if let Some(status) = site.status_change() {
+                text.push_str(&format!("{}\n", status));
+            }
+            if !site.diff.is_empty() {
+                text.push_str(&format!("{}\n", site.diff));
+            }
+        }
+
+        text
+    }
+
+    pub(crate) fn render_html(&self) -> String {
+        let mut html = format!("<p>{}</p>\n", escape(&self.period()));
+
+        for site in &self.sites {
+            html.push_str(&format!(
+                "<h3><a href=\"{}\">{}</a></h3>\n",
+                escape(&site.url),
+                escape(&site.headline())
+            ));
+            if let Some(status) = site.status_change() {
+                html.push_str(&format!("<p>{}</p>\n", escape(&status)));
+            }
+            if !site.diff.is_empty() {
+                html.push_str(&format!("<pre>{}</pre>\n", escape(&site.diff)));
+            }
+        }
+
+        html
+    }
+}
+
+impl DigestSite {
+    fn headline(&self) -> String {
+        format!(
+            "{}: {} {}",
+            self.site,
+            self.changes,
+            plural(self.changes, "change")
+        )
+    }
+
+    fn status_change(&self) -> Option<String> {
+        if self.old_status == self.new_status {
+            None
+        } else {
+            Some(format!("Status {} -> {}", self.old_status, self.new_status))
+        }
+    }
+}
+
+/// Time until the current window of `config` ends, at the next full hour or
+/// the next occurrence of `at`
+pub(crate) fn window(config: &DigestConfig) -> anyhow::Result<Duration> {
+    let now = Local::now();
+
+    let end = match config.window {
+        DigestWindow::Hourly => {
+            let hour = now.date_naive().and_hms_opt(now.hour(), 0, 0).unwrap();
+            hour + ChronoDuration::hours(1)
+        }
+        DigestWindow::Daily => {
+            let today = now.date_naive().and_time(config.at()?);
+            if today > now.naive_local() {
+                today
+            } else {
+                today + ChronoDuration::days(1)
+            }
+        }
+    };
+
+    // A time skipped by a DST change falls back to an hour later
+    let end = Local
+        .from_local_datetime(&end)
+        .earliest()
+        .or_else(|| {
+            Local
+                .from_local_datetime(&(end + ChronoDuration::hours(1)))
+                .earliest()
+        })
+        .unwrap_or_else(|| now + ChronoDuration::hours(1));
+
+    Ok((end - now).to_std().unwrap_or_default())
+}
+
+/// The first `DIGEST_LINES` lines and how many more there are
+fn condense(lines: &[String]) -> String {
+    let mut condensed = lines
+        .iter()
+        .take(DIGEST_LINES)
+        .cloned()
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    if lines.len() > DIGEST_LINES {
+        condensed.push_str(&format!("\n… {} more lines", lines.len() - DIGEST_LINES));
+    }
+
+    condensed
+}
+
+fn plural(count: usize, word: &str) -> String {
+    if count == 1 {
+        word.to_string()
+    } else {
+        format!("{}s", word)
+    }
+}
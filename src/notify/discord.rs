@@ -0,0 +1,138 @@
+use crate::{
+    config::DiscordConfig,
+    notify::{chat, check_response, digest::Digest, ChangeEvent, EventKind, Notifier},
+};
+use anyhow::Context;
+use async_trait::async_trait;
+use reqwest::{
+    multipart::{Form, Part},
+    Client,
+};
+use serde_json::json;
+
+/// Longest title and description of an embed
+const TITLE_LEN: usize = 256;
+const DESCRIPTION_LEN: usize = 4096;
+
+/// Embed colors for down sites and error statuses, other status changes and
+/// content changes or recoveries
+const RED: u32 = 0xcf222e;
+const BLUE: u32 = 0x0969da;
+const GREEN: u32 = 0x2da44e;
+
+/// Posts changes as embeds to a Discord channel webhook, diffs too long for the
+/// embed are attached as a file
+#[derive(Debug)]
+pub(crate) struct Discord {
+    url: String,
+    client: Client,
+}
+
+impl Discord {
+    pub(crate) fn new(config: &DiscordConfig, client: Client) -> Self {
+        Discord {
+            url: config.url.clone(),
+            client,
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for Discord {
+    async fn notify(&self, event: &ChangeEvent) -> anyhow::Result<()> {
+        let color = if event.kind == EventKind::Down || event.new_status >= 400 {
+            RED
+        } else if event.kind == EventKind::Recovered {
+            GREEN
+        } else if event.old_status != event.new_status {
+            BLUE
+        } else {
+            GREEN
+        };
+
+        let mut description = String::new();
+        let mut attachment = None;
+
+        if let Some(body) = &event.body {
+            description = chat::cut(body, DESCRIPTION_LEN).0;
+        } else if let Some(status) = event.status_change() {
+            description += &format!("New status '{}'\n", status);
+        }
+
+        let diff = chat::diff(event).filter(|_| event.body.is_none());
+
+        if let Some(diff) = &diff {
+            // Room for the status line and the code fence
+            let (cut_diff, cut) = chat::cut(diff, DESCRIPTION_LEN - description.len() - 20);
+            description += &chat::code_block("diff", &cut_diff);
+
+            if cut {
+                attachment = Some(diff.clone());
+            }
+        }
+
+        let mut embed = json!({
+            "title": chat::cut(&event.title, TITLE_LEN).0,
+            "url": event.url,
+            "description": description,
+            "color": color,
+            "timestamp": event.new_fetched_at.to_rfc3339(),
+        });
+
+        if attachment.is_some() {
+            embed["footer"] = json!({ "text": "Diff cut off, the full diff is attached" });
+        }
+
+        let payload = json!({ "embeds": [embed] });
+
+        let request = self.client.post(&self.url);
+        let request = match attachment {
+            Some(diff) => {
+                let file = Part::text(diff)
+                    .file_name(chat::DIFF_FILE)
+                    .mime_str("text/plain")?;
+
+                request.multipart(
+                    Form::new()
+                        .text("payload_json", payload.to_string())
+                        .part("files[0]", file),
+                )
+            }
+            None => request
+                .header("Content-Type", "application/json")
+                .body(payload.to_string()),
+        };
+
+        let response = request
+            .send()
+            .await
+            .context("Failed to call the Discord webhook")?;
+
+        check_response("Discord", response).await?;
+
+        Ok(())
+    }
+
+    async fn notify_digest(&self, digest: &Digest) -> anyhow::Result<()> {
+        let embed = json!({
+            "title": chat::cut(&digest.title(), TITLE_LEN).0,
+            "description": chat::digest_block(&digest.render_text(), DESCRIPTION_LEN),
+            "color": BLUE,
+            "timestamp": digest.end.to_rfc3339(),
+        });
+        let payload = json!({ "embeds": [embed] });
+
+        let response = self
+            .client
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .body(payload.to_string())
+            .send()
+            .await
+            .context("Failed to call the Discord webhook")?;
+
+        check_response("Discord", response).await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,568 @@
+use crate::{
+    config::{DigestConfig, NotifierConfig, NotifierKind},
+    notify::{
+        self,
+        digest::{self, Digest},
+        throttle::Throttle,
+        ChangeEvent, Notifier, Permanent, RateLimited,
+    },
+    template::{self, Templates},
+};
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+/// Delay before the first retry of a failed notification, doubled after every
+/// further failure
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+enum DispatchMessage {
+    Event {
+        notifiers: Vec<String>,
+        event: Arc<ChangeEvent>,
+    },
+    Reconfigure(BTreeMap<String, NotifierConfig>),
+    /// The batch window of a notifier is over
+    Flush {
+        notifier: String,
+        batch: u64,
+    },
+    /// The quiet hours or rate limit holding back notifications are over
+    Release {
+        notifier: String,
+    },
+}
+
+/// Handle of the dispatcher task, which routes the change events of all sites
+/// to their notifiers.
+///
+/// Every notification is delivered and retried in its own task, so a slow or
+/// failing notifier neither delays the others nor the next check. Notifiers
+/// with a batch window get the events collected during it at once, digests
+/// collect the events until the end of the hour or day and send a summary
+/// through their notifiers. Notifications falling into the quiet hours or
+/// exceeding the rate limit of a notifier are held back and sent as one
+/// digest once they are over.
+#[derive(Clone, Debug)]
+pub(crate) struct Notifications {
+    sender: mpsc::UnboundedSender<DispatchMessage>,
+}
+
+struct Dispatcher {
+    client: Client,
+    sender: mpsc::UnboundedSender<DispatchMessage>,
+    notifiers: BTreeMap<String, Arc<dyn Notifier>>,
+    digests: BTreeMap<String, DigestConfig>,
+    throttles: BTreeMap<String, Throttle>,
+    /// The configs the notifiers were built from
+    configs: BTreeMap<String, NotifierConfig>,
+    templates: BTreeMap<String, Arc<Templates>>,
+    /// Notifications held back by the throttle of their notifier, a release
+    /// is scheduled for every entry
+    held: BTreeMap<String, Vec<Payload>>,
+    /// Events waiting for the batch window of their notifier to end
+    batches: BTreeMap<String, Batch>,
+    next_batch: u64,
+}
+
+struct Batch {
+    id: u64,
+    /// When the first event arrived
+    start: DateTime<Utc>,
+    events: Vec<Arc<ChangeEvent>>,
+}
+
+/// What a notifier is asked to deliver
+enum Payload {
+    Events(Vec<Arc<ChangeEvent>>),
+    Digest(Arc<Digest>),
+}
+
+impl Notifications {
+    pub(crate) fn spawn(client: Client) -> (Self, JoinHandle<()>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let dispatcher = Dispatcher {
+            client,
+            sender: sender.clone(),
+            notifiers: BTreeMap::new(),
+            digests: BTreeMap::new(),
+            throttles: BTreeMap::new(),
+            configs: BTreeMap::new(),
+            templates: BTreeMap::new(),
+            held: BTreeMap::new(),
+            batches: BTreeMap::new(),
+            next_batch: 0,
+        };
+
+        (
+            Notifications { sender },
+            tokio::spawn(dispatcher.run(receiver)),
+        )
+    }
+
+    /// Deliver `event` to the notifiers with the given names
+    pub(crate) fn send(&self, notifiers: &[String], event: ChangeEvent) {
+        let message = DispatchMessage::Event {
+            notifiers: notifiers.to_vec(),
+            event: Arc::new(event),
+        };
+
+        if self.sender.send(message).is_err() {
+            log::error!("Failed to queue notification, the dispatcher is gone");
+        }
+    }
+
+    /// Replace all notifiers, notifications already being delivered finish
+    /// with the old ones
+    pub(crate) fn reconfigure(&self, notifiers: BTreeMap<String, NotifierConfig>) {
+        if self
+            .sender
+            .send(DispatchMessage::Reconfigure(notifiers))
+            .is_err()
+        {
+            log::error!("Failed to reconfigure notifiers, the dispatcher is gone");
+        }
+    }
+}
+
+impl Dispatcher {
+    async fn run(mut self, mut receiver: mpsc::UnboundedReceiver<DispatchMessage>) {
+        while let Some(message) = receiver.recv().await {
+            match message {
+                DispatchMessage::Event { notifiers, event } => self.dispatch(&notifiers, event),
+                DispatchMessage::Reconfigure(notifiers) => self.reconfigure(notifiers),
+                DispatchMessage::Flush { notifier, batch } => {
+                    // The batch may have been flushed by a reconfiguration
+                    if self.batches.get(&notifier).map(|pending| pending.id) == Some(batch) {
+                        self.flush(&notifier);
+                    }
+                }
+                DispatchMessage::Release { notifier } => self.release(&notifier),
+            }
+        }
+    }
+
+    fn dispatch(&mut self, names: &[String], event: Arc<ChangeEvent>) {
+        for name in names {
+            let window = if let Some(config) = self.digests.get(name) {
+                match digest::window(config) {
+                    Ok(window) => window,
+                    Err(e) => {
+                        log::error!("Failed to schedule digest {}: {:?}", name, e);
+                        continue;
+                    }
+                }
+            } else {
+                let notifier = match self.notifiers.get(name) {
+                    Some(notifier) => notifier,
+                    None => {
+                        log::error!("Failed to notify unknown notifier {}", name);
+                        continue;
+                    }
+                };
+
+                match notifier.batch_window() {
+                    Some(window) => window,
+                    None => {
+                        self.send(name, Payload::Events(vec![event.clone()]));
+                        continue;
+                    }
+                }
+            };
+
+            if let Some(batch) = self.batches.get_mut(name) {
+                batch.events.push(event.clone());
+                continue;
+            }
+
+            let id = self.next_batch;
+            self.next_batch += 1;
+
+            self.batches.insert(
+                name.clone(),
+                Batch {
+                    id,
+                    start: Utc::now(),
+                    events: vec![event.clone()],
+                },
+            );
+
+            let sender = self.sender.clone();
+            let notifier = name.clone();
+
+            tokio::spawn(async move {
+                tokio::time::sleep(window).await;
+                let _ = sender.send(DispatchMessage::Flush {
+                    notifier,
+                    batch: id,
+                });
+            });
+        }
+    }
+
+    fn flush(&mut self, name: &str) {
+        let batch = match self.batches.remove(name) {
+            Some(batch) => batch,
+            None => return,
+        };
+
+        if let Some(config) = self.digests.get(name) {
+            let digest = Arc::new(Digest::new(batch.start, &batch.events));
+
+            for to in config.to.clone() {
+                self.send(&to, Payload::Digest(digest.clone()));
+            }
+        } else {
+            self.send(name, Payload::Events(batch.events));
+        }
+    }
+
+    /// Deliver `payload` through the notifier `name`, unless its throttle
+    /// holds it back
+    fn send(&mut self, name: &str, payload: Payload) {
+        let notifier = match self.notifiers.get(name) {
+            Some(notifier) => notifier.clone(),
+            None => {
+                log::error!(
+                    "Failed to notify unknown notifier {} about {}",
+                    name,
+                    payload.subject()
+                );
+                return;
+            }
+        };
+
+        let payload = match payload {
+            Payload::Events(events) => {
+                let templates = self.templates.get(name).map(|templates| &**templates);
+                Payload::Events(
+                    events
+                        .iter()
+                        .map(|event| template::apply(event, templates))
+                        .collect(),
+                )
+            }
+            digest => digest,
+        };
+
+        self.throttled(name, notifier, payload);
+    }
+
+    /// Deliver `payload` through `notifier` unless the throttle of `name`
+    /// holds it back, every delivered payload counts once against the limit
+    fn throttled(&mut self, name: &str, notifier: Arc<dyn Notifier>, payload: Payload) {
+        let throttle = self.throttles.entry(name.to_string()).or_default();
+
+        if let Some(hold) = throttle.hold() {
+            log::info!(
+                "Holding back notification of {} about {} for {:?}",
+                name,
+                payload.subject(),
+                hold
+            );
+
+            if let Some(held) = self.held.get_mut(name) {
+                held.push(payload);
+                return;
+            }
+
+            self.held.insert(name.to_string(), vec![payload]);
+
+            let sender = self.sender.clone();
+            let notifier = name.to_string();
+
+            tokio::spawn(async move {
+                tokio::time::sleep(hold).await;
+                let _ = sender.send(DispatchMessage::Release { notifier });
+            });
+
+            return;
+        }
+
+        throttle.record();
+        tokio::spawn(deliver(name.to_string(), notifier, payload));
+    }
+
+    /// Send the notifications held back for `name`, several change events as
+    /// one digest
+    fn release(&mut self, name: &str) {
+        let held = match self.held.remove(name) {
+            Some(held) => held,
+            None => return,
+        };
+        let notifier = match self.notifiers.get(name) {
+            Some(notifier) => notifier.clone(),
+            None => return,
+        };
+
+        let mut events = Vec::new();
+        let mut payloads = Vec::new();
+
+        for payload in held {
+            match payload {
+                Payload::Events(batch) => events.extend(batch),
+                digest => payloads.push(digest),
+            }
+        }
+
+        if events.len() > 1 {
+            log::info!(
+                "Sending {} held back changes to {} as a digest",
+                events.len(),
+                name
+            );
+            let start = events
+                .iter()
+                .map(|event| event.new_fetched_at)
+                .min()
+                .unwrap_or_else(Utc::now);
+            payloads.insert(0, Payload::Digest(Arc::new(Digest::new(start, &events))));
+        } else if !events.is_empty() {
+            payloads.insert(0, Payload::Events(events));
+        }
+
+        // Templates were applied before holding the events back
+        for payload in payloads {
+            self.throttled(name, notifier.clone(), payload);
+        }
+    }
+
+    fn reconfigure(&mut self, configs: BTreeMap<String, NotifierConfig>) {
+        // Digests that still exist keep collecting until their window ends
+        let pending: Vec<String> = self
+            .batches
+            .keys()
+            .filter(|name| {
+                !matches!(
+                    configs.get(*name).map(|config| &config.kind),
+                    Some(NotifierKind::Digest(_))
+                )
+            })
+            .cloned()
+            .collect();
+        for name in pending {
+            self.flush(&name);
+        }
+
+        self.notifiers.clear();
+        self.digests.clear();
+        self.templates.clear();
+        let mut throttles = std::mem::take(&mut self.throttles);
+        let previous = std::mem::take(&mut self.configs);
+
+        // Held back notifications are released with the new throttles, or
+        // dropped if their notifier is gone. A throttle with unchanged quiet
+        // hours and rate limit keeps counting, so a reload does not reset it.
+        for (name, config) in configs {
+            if let NotifierKind::Digest(digest) = config.kind {
+                self.digests.insert(name, digest);
+                continue;
+            }
+
+            let kept = previous
+                .get(&name)
+                .filter(|previous| {
+                    previous.quiet_hours == config.quiet_hours
+                        && previous.rate_limit == config.rate_limit
+                })
+                .and_then(|_| throttles.remove(&name));
+            let throttle = match kept {
+                Some(throttle) => Ok(throttle),
+                None => Throttle::new(&config),
+            };
+
+            let built = throttle.and_then(|throttle| {
+                let templates = Templates::new(config.title.as_deref(), config.body.as_deref())?;
+                Ok((notify::build(&config, &self.client)?, throttle, templates))
+            });
+
+            match built {
+                Ok((notifier, throttle, templates)) => {
+                    self.notifiers.insert(name.clone(), notifier);
+                    self.throttles.insert(name.clone(), throttle);
+                    if let Some(templates) = templates {
+                        self.templates.insert(name.clone(), Arc::new(templates));
+                    }
+                    self.configs.insert(name, config);
+                }
+                Err(e) => log::error!("Failed to set up notifier {}: {:?}", name, e),
+            }
+        }
+    }
+}
+
+impl Payload {
+    /// The changed sites, for logging
+    fn subject(&self) -> String {
+        match self {
+            Payload::Events(events) => events
+                .iter()
+                .map(|event| event.site.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+            Payload::Digest(_) => "the digest".to_string(),
+        }
+    }
+}
+
+/// Deliver `payload` as one notification, retrying failures. Notifiers
+/// without a batch window only ever get single events or digests, so a retry
+/// does not send an event already delivered again.
+async fn deliver(name: String, notifier: Arc<dyn Notifier>, payload: Payload) {
+    let sites = payload.subject();
+
+    let retries = notifier.retries();
+    let mut delay = RETRY_DELAY;
+
+    for attempt in 0..=retries {
+        let result = match &payload {
+            Payload::Events(events) => match events.as_slice() {
+                [event] => notifier.notify(event).await,
+                events => notifier.notify_batch(events).await,
+            },
+            Payload::Digest(digest) => notifier.notify_digest(digest).await,
+        };
+
+        match result {
+            Ok(()) => return,
+            Err(e) if e.is::<Permanent>() => {
+                log::error!("Failed to notify {} about {}: {:?}", name, sites, e);
+                return;
+            }
+            Err(e) if attempt < retries => {
+                let wait = e
+                    .downcast_ref::<RateLimited>()
+                    .and_then(|limited| limited.retry_after)
+                    .unwrap_or(delay);
+
+                log::warn!(
+                    "Failed to notify {} about {}, retrying in {:?}: {:?}",
+                    name,
+                    sites,
+                    wait,
+                    e
+                );
+                tokio::time::sleep(wait).await;
+                delay *= 2;
+            }
+            Err(e) => log::error!(
+                "Failed to notify {} about {}, giving up after {} attempts: {:?}",
+                name,
+                sites,
+                retries + 1,
+                e
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{RateLimit, WebhookConfig},
+        test_util::{event, serve},
+    };
+
+    fn webhook(url: String, retries: u32, rate_limit: Option<RateLimit>) -> NotifierConfig {
+        NotifierConfig {
+            kind: NotifierKind::Webhook(WebhookConfig {
+                url,
+                headers: BTreeMap::new(),
+                secret: None,
+                timeout: 5,
+                retries,
+            }),
+            quiet_hours: None,
+            rate_limit,
+            title: None,
+            body: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn held_back_events_are_sent_as_one_digest() {
+        let (url, server) = serve(vec![200, 200], "").await;
+        let (notifications, _) = Notifications::spawn(Client::new());
+
+        let config = webhook(url, 0, Some(RateLimit { max: 1, period: 1 }));
+        notifications.reconfigure(BTreeMap::from([("hook".to_string(), config)]));
+
+        let hook = ["hook".to_string()];
+        for site in ["news", "blog", "shop"] {
+            notifications.send(&hook, event(site));
+        }
+
+        let requests = tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap();
+        let first: serde_json::Value = serde_json::from_str(&requests[0].1).unwrap();
+        let second: serde_json::Value = serde_json::from_str(&requests[1].1).unwrap();
+
+        assert_eq!(first["site"], "news");
+        assert_eq!(second["sites"][0]["site"], "blog");
+        assert_eq!(second["sites"][1]["site"], "shop");
+    }
+
+    #[tokio::test]
+    async fn reload_keeps_the_rate_limit() {
+        let (url, server) = serve(vec![200, 200], "").await;
+        let (notifications, _) = Notifications::spawn(Client::new());
+        let hook = ["hook".to_string()];
+        let limit = Some(RateLimit { max: 1, period: 2 });
+
+        let config = webhook(url.clone(), 0, limit.clone());
+        notifications.reconfigure(BTreeMap::from([("hook".to_string(), config)]));
+        notifications.send(&hook, event("news"));
+
+        // Only the title changes, the next event is still held back
+        let mut config = webhook(url, 0, limit);
+        config.title = Some("{{ site }} changed".to_string());
+        notifications.reconfigure(BTreeMap::from([("hook".to_string(), config)]));
+        let start = std::time::Instant::now();
+        notifications.send(&hook, event("blog"));
+
+        let requests = tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(1));
+
+        let second: serde_json::Value = serde_json::from_str(&requests[1].1).unwrap();
+        assert_eq!(second["site"], "blog");
+        assert_eq!(second["title"], "blog changed");
+    }
+
+    #[tokio::test]
+    async fn events_are_delivered_and_retried_one_by_one() {
+        let (url, server) = serve(vec![429, 200, 200], r#"{"retry_after": 0}"#).await;
+        let (notifications, _) = Notifications::spawn(Client::new());
+
+        let config = webhook(url, 1, None);
+        notifications.reconfigure(BTreeMap::from([("hook".to_string(), config)]));
+
+        let hook = ["hook".to_string()];
+        notifications.send(&hook, event("news"));
+        notifications.send(&hook, event("blog"));
+
+        let requests = tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut sites = requests
+            .iter()
+            .map(|(_, body)| {
+                let body: serde_json::Value = serde_json::from_str(body).unwrap();
+                body["site"].as_str().unwrap().to_string()
+            })
+            .collect::<Vec<_>>();
+        sites.sort();
+
+        // Only the event answered with 429 is sent again
+        assert!(sites == ["blog", "blog", "news"] || sites == ["blog", "news", "news"]);
+    }
+}
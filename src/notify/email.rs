@@ -0,0 +1,280 @@
+use crate::{
+    config::{EmailConfig, SmtpTls},
+    diff::{escape, OutputFormat},
+    notify::{digest::Digest, ChangeEvent, Notifier},
+};
+use anyhow::Context;
+use async_trait::async_trait;
+use lettre::{
+    message::{Mailbox, MultiPart},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use std::{sync::Arc, time::Duration};
+
+/// Colors for the `<del>` and `<ins>` elements of the HTML diffs
+const STYLE: &str = "del { background: #ffebe9; color: #82071e; }
+ins { background: #dafbe1; color: #116329; text-decoration: none; }
+pre { white-space: pre-wrap; }";
+
+/// Mails changes through an SMTP server, with the unified diff as plain text
+/// and a colored HTML diff as alternative.
+///
+/// Changes of several sites arriving within `batch` seconds are sent as one
+/// mail.
+#[derive(Debug)]
+pub(crate) struct Email {
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    batch: Duration,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl Email {
+    pub(crate) fn new(config: &EmailConfig) -> anyhow::Result<Self> {
+        let mut transport = match config.tls {
+            SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host),
+            SmtpTls::Starttls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?
+            }
+            SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)?,
+        }
+        .port(config.port());
+
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        Ok(Email {
+            from: config.from.parse()?,
+            to: config
+                .to
+                .iter()
+                .map(|to| to.parse())
+                .collect::<Result<_, _>>()?,
+            batch: Duration::from_secs(config.batch),
+            transport: transport.build(),
+        })
+    }
+
+    async fn send_events(&self, subject: String, events: &[&ChangeEvent]) -> anyhow::Result<()> {
+        let text = events
+            .iter()
+            .map(|event| plain_text(event))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let html = events.iter().map(|event| html(event)).collect::<String>();
+
+        self.send(subject, text, &html).await
+    }
+
+    /// Send a mail with `text` and the HTML `body` as alternatives
+    async fn send(&self, subject: String, text: String, body: &str) -> anyhow::Result<()> {
+        let mut message = Message::builder().from(self.from.clone()).subject(subject);
+
+        for to in &self.to {
+            message = message.to(to.clone());
+        }
+
+        let html = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<style>\n{}\n</style>\n</head>\n<body>\n{}</body>\n</html>\n",
+            STYLE,
+            body
+        );
+
+        let message = message.multipart(MultiPart::alternative_plain_html(text, html))?;
+
+        self.transport
+            .send(message)
+            .await
+            .context("Failed to send mail")?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Notifier for Email {
+    async fn notify(&self, event: &ChangeEvent) -> anyhow::Result<()> {
+        self.send_events(event.title.clone(), &[event]).await
+    }
+
+    async fn notify_batch(&self, events: &[Arc<ChangeEvent>]) -> anyhow::Result<()> {
+        let sites = events
+            .iter()
+            .map(|event| event.site.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let events = events.iter().map(|event| &**event).collect::<Vec<_>>();
+
+        self.send_events(
+            format!("{} Sites Updated: {}", events.len(), sites),
+            &events,
+        )
+        .await
+    }
+
+    async fn notify_digest(&self, digest: &Digest) -> anyhow::Result<()> {
+        self.send(digest.title(), digest.render_text(), &digest.render_html())
+            .await
+    }
+
+    fn batch_window(&self) -> Option<Duration> {
+        if self.batch == Duration::ZERO {
+            None
+        } else {
+            Some(self.batch)
+        }
+    }
+}
+
+fn plain_text(event: &ChangeEvent) -> String {
+    let mut text = format!("{}\n{}\n\n", event.title, event.url);
+
+    if let Some(body) = &event.body {
+        return text + body + "\n";
+    }
+
+    if let Some(status) = event.status_change() {
+        text += &format!("New status '{}'\n\n", status);
+    }
+
+    if let Some(diff) = event.render(OutputFormat::Unified) {
+        text += &diff;
+    }
+
+    text
+}
+
+fn html(event: &ChangeEvent) -> String {
+    let mut html = format!(
+        "<h2><a href=\"{}\">{}</a></h2>\n",
+        escape(&event.url),
+        escape(&event.title)
+    );
+
+    if let Some(body) = &event.body {
+        return html + &format!("<pre>{}</pre>\n", escape(body));
+    }
+
+    if let Some(status) = event.status_change() {
+        html += &format!("<p>New status '{}'</p>\n", escape(&status));
+    }
+
+    if let Some(diff) = event.render(OutputFormat::Html) {
+        html += &diff;
+    }
+
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::EmailConfig, test_util::event};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+        task::JoinHandle,
+    };
+
+    /// A local SMTP sink accepting one mail, the task returns the commands
+    /// and the message it got
+    async fn sink() -> (u16, JoinHandle<(Vec<String>, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut commands = Vec::new();
+            let mut message = String::new();
+
+            stream.write_all(b"220 sink\r\n").await.unwrap();
+
+            loop {
+                let mut line = String::new();
+                if stream.read_line(&mut line).await.unwrap() == 0 {
+                    break;
+                }
+                let command = line.trim_end().to_string();
+
+                let reply: &[u8] = match command.split(' ').next().unwrap() {
+                    "EHLO" => b"250 sink\r\n",
+                    "DATA" => {
+                        stream.write_all(b"354 go ahead\r\n").await.unwrap();
+                        loop {
+                            let mut line = String::new();
+                            stream.read_line(&mut line).await.unwrap();
+                            if line == ".\r\n" {
+                                break;
+                            }
+                            message += &line;
+                        }
+                        b"250 queued\r\n"
+                    }
+                    "QUIT" => {
+                        stream.write_all(b"221 bye\r\n").await.unwrap();
+                        commands.push(command);
+                        break;
+                    }
+                    _ => b"250 ok\r\n",
+                };
+
+                stream.write_all(reply).await.unwrap();
+                commands.push(command);
+            }
+
+            (commands, message)
+        });
+
+        (port, server)
+    }
+
+    fn email(port: u16) -> Email {
+        Email::new(&EmailConfig {
+            host: "127.0.0.1".to_string(),
+            port: Some(port),
+            tls: SmtpTls::None,
+            username: None,
+            password: None,
+            from: "Checker <checker@example.org>".to_string(),
+            to: vec!["a@example.org".to_string(), "b@example.org".to_string()],
+            batch: 0,
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn sends_text_and_html() {
+        let (port, sink) = sink().await;
+
+        email(port).notify(&event("news")).await.unwrap();
+
+        let (commands, message) = sink.await.unwrap();
+
+        assert!(commands.contains(&"MAIL FROM:<checker@example.org>".to_string()));
+        assert!(commands.contains(&"RCPT TO:<a@example.org>".to_string()));
+        assert!(commands.contains(&"RCPT TO:<b@example.org>".to_string()));
+        assert!(message.contains("Subject: news Updated\r\n"));
+        assert!(message.contains("multipart/alternative"));
+        assert!(message.contains("Content-Type: text/plain"));
+        assert!(message.contains("Content-Type: text/html"));
+    }
+
+    #[tokio::test]
+    async fn batches_in_one_mail() {
+        let (port, sink) = sink().await;
+
+        email(port)
+            .notify_batch(&[Arc::new(event("news")), Arc::new(event("blog"))])
+            .await
+            .unwrap();
+
+        let (_, message) = sink.await.unwrap();
+
+        assert!(message.contains("Subject: 2 Sites Updated: news, blog\r\n"));
+        assert!(message.contains("http://news.example/"));
+        assert!(message.contains("http://blog.example/"));
+    }
+}
@@ -0,0 +1,138 @@
+use crate::{
+    config::ExecConfig,
+    notify::{digest::Digest, ChangeEvent, Notifier},
+};
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use std::{ffi::OsString, process::Stdio, time::Duration};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Runs a command for every change.
+///
+/// The command gets the site, title and statuses in its environment, the paths
+/// of the response bodies before and after the change in `OLD_SNAPSHOT` and
+/// `NEW_SNAPSHOT` and the diff, or the templated body, on stdin. Its stderr is
+/// logged, a non-zero exit status or running longer than the timeout fails the
+/// notification. Digests are written to stdin as text.
+#[derive(Debug)]
+pub(crate) struct Exec {
+    command: Vec<String>,
+    timeout: Duration,
+}
+
+impl Exec {
+    pub(crate) fn new(config: &ExecConfig) -> Self {
+        Exec {
+            command: config.command.clone(),
+            timeout: Duration::from_secs(config.timeout),
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for Exec {
+    async fn notify(&self, event: &ChangeEvent) -> anyhow::Result<()> {
+        // Removed with the snapshots when dropped
+        let dir = tempfile::Builder::new()
+            .prefix("site-checker-")
+            .tempdir()
+            .context("Failed to create snapshot dir")?;
+        let old_snapshot = dir.path().join("old");
+        let new_snapshot = dir.path().join("new");
+        tokio::fs::write(&old_snapshot, &event.old_body).await?;
+        tokio::fs::write(&new_snapshot, &event.new_body).await?;
+
+        let id = |id: Option<i64>| id.map(|id| id.to_string()).unwrap_or_default();
+
+        let env = vec![
+            ("EVENT", event.kind.to_string().into()),
+            ("SITE_NAME", event.site.clone().into()),
+            ("SITE_URL", event.url.clone().into()),
+            ("TITLE", event.title.clone().into()),
+            ("OLD_STATUS", event.old_status.to_string().into()),
+            ("NEW_STATUS", event.new_status.to_string().into()),
+            ("OLD_SNAPSHOT", old_snapshot.into()),
+            ("NEW_SNAPSHOT", new_snapshot.into()),
+            ("OLD_FETCHED_AT", event.old_fetched_at.to_rfc3339().into()),
+            ("NEW_FETCHED_AT", event.new_fetched_at.to_rfc3339().into()),
+            ("CHANGE_ID", id(event.change_id).into()),
+        ];
+
+        let input = event.body.clone().or_else(|| event.diff.clone());
+
+        self.run(&event.site, env, input.unwrap_or_default()).await
+    }
+
+    async fn notify_digest(&self, digest: &Digest) -> anyhow::Result<()> {
+        let sites = digest
+            .sites
+            .iter()
+            .map(|site| site.site.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let env = vec![
+            ("DIGEST_START", digest.start.to_rfc3339().into()),
+            ("DIGEST_END", digest.end.to_rfc3339().into()),
+            ("SITE_NAMES", sites.into()),
+        ];
+
+        self.run("the digest", env, digest.render_text()).await
+    }
+}
+
+impl Exec {
+    /// Run the command with `env` and `input` on stdin, logging its stderr
+    /// as being about `subject`
+    async fn run(
+        &self,
+        subject: &str,
+        env: Vec<(&str, OsString)>,
+        input: String,
+    ) -> anyhow::Result<()> {
+        let program = &self.command[0];
+
+        let mut child = tokio::process::Command::new(program)
+            .args(&self.command[1..])
+            .envs(env)
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Failed to run {}", program))?;
+
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+
+        let run = async {
+            // A command not reading its stdin closes it early, that is fine
+            let write = async {
+                let _ = stdin.write_all(input.as_bytes()).await;
+                drop(stdin);
+            };
+
+            let mut errors = Vec::new();
+            let (_, read, status) =
+                tokio::join!(write, stderr.read_to_end(&mut errors), child.wait());
+            read?;
+
+            Ok::<_, std::io::Error>((status?, errors))
+        };
+
+        let (status, errors) = match tokio::time::timeout(self.timeout, run).await {
+            Ok(result) => result.with_context(|| format!("Failed to run {}", program))?,
+            Err(_) => bail!("{} did not finish within {:?}", program, self.timeout),
+        };
+
+        let errors = String::from_utf8_lossy(&errors);
+        for line in errors.lines().filter(|line| !line.trim().is_empty()) {
+            log::warn!("{} for {}: {}", program, subject, line);
+        }
+
+        if !status.success() {
+            bail!("{} exited with {}", program, status);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+use crate::{
+    config::MatrixConfig,
+    diff::escape,
+    notify::{chat, check_response, digest::Digest, ChangeEvent, Notifier},
+};
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use reqwest::{Client, Url};
+use serde_json::{json, Value};
+
+/// Longest diff sent in a message, longer diffs are uploaded as a file
+const DIFF_LEN: usize = 16000;
+
+/// Sends changes as `m.text` messages with an HTML `formatted_body` to a Matrix
+/// room, diffs too long for a message follow as an `m.file`
+#[derive(Debug)]
+pub(crate) struct Matrix {
+    homeserver: Url,
+    room: String,
+    token: String,
+    client: Client,
+}
+
+impl Matrix {
+    pub(crate) fn new(config: &MatrixConfig, client: Client) -> anyhow::Result<Self> {
+        Ok(Matrix {
+            homeserver: config.homeserver.parse()?,
+            room: config.room.clone(),
+            token: config.token.clone(),
+            client,
+        })
+    }
+
+    fn endpoint(&self, segments: &[&str]) -> anyhow::Result<Url> {
+        let mut url = self.homeserver.clone();
+
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Invalid homeserver url {}", self.homeserver))?
+            .pop_if_empty()
+            .extend(segments);
+
+        Ok(url)
+    }
+
+    /// Send a message event, `txn` makes retries of the same message
+    /// idempotent
+    async fn send(&self, txn: &str, content: Value) -> anyhow::Result<()> {
+        let url = self.endpoint(&[
+            "_matrix",
+            "client",
+            "v3",
+            "rooms",
+            &self.room,
+            "send",
+            "m.room.message",
+            txn,
+        ])?;
+
+        let response = self
+            .client
+            .put(url)
+            .bearer_auth(&self.token)
+            .header("Content-Type", "application/json")
+            .body(content.to_string())
+            .send()
+            .await
+            .context("Failed to send Matrix message")?;
+
+        check_response("Matrix", response).await?;
+
+        Ok(())
+    }
+
+    /// Upload `text` to the media repository, returning its `mxc://` uri
+    async fn upload(&self, text: String) -> anyhow::Result<String> {
+        let mut url = self.endpoint(&["_matrix", "media", "v3", "upload"])?;
+        url.query_pairs_mut()
+            .append_pair("filename", chat::DIFF_FILE);
+
+        let response = self
+            .client
+            .post(url)
+            .bearer_auth(&self.token)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(text)
+            .send()
+            .await
+            .context("Failed to upload diff to Matrix")?;
+
+        let response = check_response("Matrix", response).await?;
+        let body: Value = serde_json::from_slice(&response.bytes().await?)?;
+
+        body.get("content_uri")
+            .and_then(|uri| uri.as_str())
+            .map(str::to_string)
+            .context("Matrix upload response without content_uri")
+    }
+}
+
+#[async_trait]
+impl Notifier for Matrix {
+    async fn notify(&self, event: &ChangeEvent) -> anyhow::Result<()> {
+        let title = event.title.clone();
+
+        let mut body = format!("{}\n{}\n", title, event.url);
+        let mut html = format!(
+            "<h4><a href=\"{}\">{}</a></h4>\n",
+            escape(&event.url),
+            escape(&title)
+        );
+
+        if let Some(template) = &event.body {
+            let (template, _) = chat::cut(template, DIFF_LEN);
+            body += &format!("\n{}", template);
+            html += &format!("<p>{}</p>\n", escape(&template).replace('\n', "<br>"));
+        } else if let Some(status) = event.status_change() {
+            body += &format!("New status '{}'\n", status);
+            html += &format!("<p>New status '{}'</p>\n", escape(&status));
+        }
+
+        let diff = chat::diff(event).filter(|_| event.body.is_none());
+        let mut attachment = None;
+
+        if let Some(diff) = &diff {
+            let (cut_diff, cut) = chat::cut(diff, DIFF_LEN);
+
+            body += &format!("\n{}", cut_diff);
+            html += &format!(
+                "<pre><code class=\"language-diff\">{}</code></pre>\n",
+                escape(&cut_diff)
+            );
+
+            if cut {
+                body += "\nDiff cut off, the full diff follows as a file";
+                html += "<p>Diff cut off, the full diff follows as a file</p>\n";
+                attachment = Some(diff.clone());
+            }
+        }
+
+        // Stable across retries, so the homeserver drops duplicates
+        let txn = format!("{}.{}", event.site, event.new_fetched_at.timestamp_micros());
+
+        self.send(
+            &format!("{}.message", txn),
+            json!({
+                "msgtype": "m.text",
+                "body": body,
+                "format": "org.matrix.custom.html",
+                "formatted_body": html,
+            }),
+        )
+        .await?;
+
+        if let Some(diff) = attachment {
+            let size = diff.len();
+            let uri = self.upload(diff).await?;
+
+            self.send(
+                &format!("{}.diff", txn),
+                json!({
+                    "msgtype": "m.file",
+                    "body": chat::DIFF_FILE,
+                    "filename": chat::DIFF_FILE,
+                    "url": uri,
+                    "info": { "mimetype": "text/plain", "size": size },
+                }),
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn notify_digest(&self, digest: &Digest) -> anyhow::Result<()> {
+        let title = digest.title();
+        let text = digest.render_text();
+        let (cut_text, cut) = chat::cut(&text, DIFF_LEN);
+
+        let body = format!("{}\n{}", title, cut_text);
+        let html = if cut {
+            format!(
+                "<h4>{}</h4>\n<pre>{}\n…</pre>\n",
+                escape(&title),
+                escape(&cut_text)
+            )
+        } else {
+            format!("<h4>{}</h4>\n{}", escape(&title), digest.render_html())
+        };
+
+        self.send(
+            &format!("digest.{}", digest.end.timestamp_micros()),
+            json!({
+                "msgtype": "m.text",
+                "body": body,
+                "format": "org.matrix.custom.html",
+                "formatted_body": html,
+            }),
+        )
+        .await
+    }
+}
@@ -0,0 +1,92 @@
+use crate::{
+    config::ChatWebhookConfig,
+    notify::{chat, check_response, digest::Digest, ChangeEvent, Notifier},
+};
+use anyhow::Context;
+use async_trait::async_trait;
+use reqwest::Client;
+use serde_json::json;
+
+/// Longest diff included in a post, Mattermost rejects posts over 16383
+/// characters
+const DIFF_LEN: usize = 15000;
+
+/// Posts changes as Markdown to a Mattermost incoming webhook.
+///
+/// Incoming webhooks cannot upload files, diffs too long for one post are cut
+/// off and followed by a link to the full diff.
+#[derive(Debug)]
+pub(crate) struct Mattermost {
+    url: String,
+    link: Option<String>,
+    client: Client,
+}
+
+impl Mattermost {
+    pub(crate) fn new(config: &ChatWebhookConfig, client: Client) -> Self {
+        Mattermost {
+            url: config.url.clone(),
+            link: config.link.clone(),
+            client,
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for Mattermost {
+    async fn notify(&self, event: &ChangeEvent) -> anyhow::Result<()> {
+        let mut text = format!("#### [{}]({})\n", event.title, event.url);
+
+        if let Some(body) = &event.body {
+            text += &chat::cut(body, DIFF_LEN).0;
+            return self.post(text).await;
+        }
+
+        if let Some(status) = event.status_change() {
+            text += &format!("New status '{}'\n", status);
+        }
+
+        if let Some(diff) = chat::diff(event) {
+            let (diff, cut) = chat::cut(&diff, DIFF_LEN);
+            text += &chat::code_block("diff", &diff);
+
+            if cut {
+                text += &match chat::link(self.link.as_deref(), event) {
+                    Some(link) => format!("\nDiff cut off, [see the full diff]({})", link),
+                    None => format!("\nDiff cut off, run `{}`", chat::history_hint(event)),
+                };
+            }
+        }
+
+        self.post(text).await
+    }
+
+    async fn notify_digest(&self, digest: &Digest) -> anyhow::Result<()> {
+        let text = format!(
+            "#### {}\n{}",
+            digest.title(),
+            chat::digest_block(&digest.render_text(), DIFF_LEN)
+        );
+
+        self.post(text).await
+    }
+}
+
+impl Mattermost {
+    async fn post(&self, text: String) -> anyhow::Result<()> {
+        let payload = json!({ "text": text });
+
+        let response = self
+            .client
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .body(payload.to_string())
+            .send()
+            .await
+            .context("Failed to call the Mattermost webhook")?;
+
+        check_response("Mattermost", response).await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,269 @@
+use crate::{
+    config::{NotifierConfig, NotifierKind},
+    diff::{ContentDiff, DiffHeader, OutputFormat},
+    notify::digest::Digest,
+    template::Templates,
+};
+use anyhow::bail;
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use reqwest::{header::RETRY_AFTER, Client, Response, StatusCode};
+use serde::Serialize;
+use std::{fmt, sync::Arc, time::Duration};
+
+mod chat;
+pub(crate) mod desktop;
+pub(crate) mod digest;
+pub(crate) mod discord;
+pub(crate) mod dispatch;
+pub(crate) mod email;
+pub(crate) mod exec;
+pub(crate) mod matrix;
+pub(crate) mod mattermost;
+pub(crate) mod slack;
+pub(crate) mod stdout;
+pub(crate) mod throttle;
+pub(crate) mod webhook;
+
+/// Attempts after the first one failed, unless the notifier says otherwise
+const DEFAULT_RETRIES: u32 = 3;
+
+/// Everything known about one detected change of a site
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct ChangeEvent {
+    pub(crate) kind: EventKind,
+    pub(crate) site: String,
+    pub(crate) url: String,
+    pub(crate) title: String,
+    /// Replaces the description and diff, tells about the outage of down and
+    /// recovered events or is rendered from a template
+    pub(crate) body: Option<String>,
+    pub(crate) old_status: u16,
+    pub(crate) new_status: u16,
+    pub(crate) diff: Option<String>,
+    pub(crate) old_fetched_at: DateTime<Utc>,
+    pub(crate) new_fetched_at: DateTime<Utc>,
+    /// History ids of the fetches before and after the change
+    pub(crate) old_snapshot: Option<i64>,
+    pub(crate) new_snapshot: Option<i64>,
+    /// History id of the change, for `history diff`
+    pub(crate) change_id: Option<i64>,
+    /// Why the site is down
+    pub(crate) error: Option<String>,
+    /// Start of the outage of down and recovered events
+    pub(crate) down_since: Option<DateTime<Utc>>,
+    /// Seconds the site was down, for recovered events
+    pub(crate) outage: Option<u64>,
+    /// The structured diff, for notifiers needing another format than `diff`
+    #[serde(skip)]
+    pub(crate) changes: Option<Arc<ContentDiff>>,
+    /// Unchanged lines around every change in unified diffs
+    #[serde(skip)]
+    pub(crate) context: usize,
+    /// The response bodies before and after the change
+    #[serde(skip)]
+    pub(crate) old_body: Bytes,
+    #[serde(skip)]
+    pub(crate) new_body: Bytes,
+    /// Title and body templates of the site
+    #[serde(skip)]
+    pub(crate) templates: Option<Arc<Templates>>,
+}
+
+/// What happened to a site
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum EventKind {
+    /// The status or content changed
+    Change,
+    /// Checks failed `down_after` times in a row
+    Down,
+    /// The first successful check after being down
+    Recovered,
+}
+
+/// A channel change events and digests are delivered through
+#[async_trait]
+pub(crate) trait Notifier: fmt::Debug + Send + Sync {
+    async fn notify(&self, event: &ChangeEvent) -> anyhow::Result<()>;
+
+    /// Deliver the summary of a digest window
+    async fn notify_digest(&self, digest: &Digest) -> anyhow::Result<()>;
+
+    /// Deliver the events collected during `batch_window` as one message.
+    /// Notifiers without a batch window get every event on its own instead.
+    async fn notify_batch(&self, events: &[Arc<ChangeEvent>]) -> anyhow::Result<()> {
+        for event in events {
+            self.notify(event).await?;
+        }
+
+        Ok(())
+    }
+
+    /// How often a failed notification is retried
+    fn retries(&self) -> u32 {
+        DEFAULT_RETRIES
+    }
+
+    /// How long to wait for more events after one arrived, `None` delivers
+    /// every event on its own right away
+    fn batch_window(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// An error that would happen again on a retry
+#[derive(Debug)]
+pub(crate) struct Permanent(pub(crate) String);
+
+/// A `429` response, optionally telling how long to wait before the retry
+#[derive(Debug)]
+pub(crate) struct RateLimited {
+    pub(crate) service: String,
+    pub(crate) retry_after: Option<Duration>,
+}
+
+impl ChangeEvent {
+    /// The diff in `format`, `None` if only the status changed
+    pub(crate) fn render(&self, format: OutputFormat) -> Option<String> {
+        let header = DiffHeader {
+            site: &self.site,
+            old_time: self.old_fetched_at.timestamp(),
+            new_time: self.new_fetched_at.timestamp(),
+            context: self.context,
+        };
+
+        self.changes
+            .as_ref()
+            .map(|changes| changes.render(format, &header))
+    }
+
+    /// The new status if it changed, e.g. `404 Not Found`
+    pub(crate) fn status_change(&self) -> Option<String> {
+        if self.old_status == self.new_status {
+            return None;
+        }
+
+        Some(
+            StatusCode::from_u16(self.new_status)
+                .map(|status| status.to_string())
+                .unwrap_or_else(|_| self.new_status.to_string()),
+        )
+    }
+
+    pub(crate) fn description(&self) -> String {
+        if let Some(body) = &self.body {
+            body.clone()
+        } else if let Some(status) = self.status_change() {
+            if let Some(diff) = &self.diff {
+                format!("New status '{}' and site content changed\n{}", status, diff)
+            } else {
+                format!("New status '{}'", status)
+            }
+        } else if let Some(diff) = &self.diff {
+            format!("Site content changed\n{}", diff)
+        } else {
+            "Site content changed".to_string()
+        }
+    }
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EventKind::Change => "change",
+            EventKind::Down => "down",
+            EventKind::Recovered => "recovered",
+        })
+    }
+}
+
+impl fmt::Display for Permanent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Permanent {}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.retry_after {
+            Some(delay) => write!(f, "{} is rate limited for {:?}", self.service, delay),
+            None => write!(f, "{} is rate limited", self.service),
+        }
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+/// Turn an unsuccessful response of `service` into an error telling the
+/// dispatcher whether and when to retry.
+///
+/// The delay of a `429` is taken from `Retry-After` or from the
+/// `retry_after` (seconds) or `retry_after_ms` field of a JSON body, as sent
+/// by Discord and Matrix.
+pub(crate) async fn check_response(service: &str, response: Response) -> anyhow::Result<Response> {
+    let status = response.status();
+
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        let header = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<f64>().ok());
+
+        let body = response.bytes().await.ok();
+        let body = body.and_then(|body| serde_json::from_slice::<serde_json::Value>(&body).ok());
+        let field = body.and_then(|body| {
+            body.get("retry_after")
+                .and_then(|value| value.as_f64())
+                .or_else(|| {
+                    body.get("retry_after_ms")
+                        .and_then(|value| value.as_f64())
+                        .map(|ms| ms / 1000.0)
+                })
+        });
+
+        return Err(RateLimited {
+            service: service.to_string(),
+            retry_after: header
+                .or(field)
+                .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+                .map(Duration::from_secs_f64),
+        }
+        .into());
+    }
+
+    let body = response.text().await.unwrap_or_default();
+    let body = body.trim().chars().take(200).collect::<String>();
+    let message = format!("{} responded with {}: {}", service, status, body);
+
+    if status.is_client_error() {
+        Err(Permanent(message).into())
+    } else {
+        Err(anyhow::anyhow!(message))
+    }
+}
+
+pub(crate) fn build(config: &NotifierConfig, client: &Client) -> anyhow::Result<Arc<dyn Notifier>> {
+    Ok(match &config.kind {
+        NotifierKind::Desktop(config) => Arc::new(desktop::Desktop::new(config)),
+        NotifierKind::Webhook(config) => Arc::new(webhook::Webhook::new(config, client.clone())?),
+        NotifierKind::Email(config) => Arc::new(email::Email::new(config)?),
+        NotifierKind::Exec(config) => Arc::new(exec::Exec::new(config)),
+        NotifierKind::Stdout => Arc::new(stdout::Stdout),
+        NotifierKind::Slack(config) => Arc::new(slack::Slack::new(config, client.clone())),
+        NotifierKind::Mattermost(config) => {
+            Arc::new(mattermost::Mattermost::new(config, client.clone()))
+        }
+        NotifierKind::Discord(config) => Arc::new(discord::Discord::new(config, client.clone())),
+        NotifierKind::Matrix(config) => Arc::new(matrix::Matrix::new(config, client.clone())?),
+        NotifierKind::Digest(_) => bail!("Digests are run by the dispatcher"),
+    })
+}
@@ -0,0 +1,131 @@
+use crate::{
+    config::ChatWebhookConfig,
+    notify::{chat, check_response, digest::Digest, ChangeEvent, Notifier},
+};
+use anyhow::Context;
+use async_trait::async_trait;
+use reqwest::Client;
+use serde_json::json;
+
+/// Longest text of a section block
+const SECTION_LEN: usize = 3000;
+
+/// Longest plain text of a header block
+const HEADER_LEN: usize = 150;
+
+/// Posts changes as Block Kit messages to a Slack incoming webhook.
+///
+/// Incoming webhooks cannot upload files, diffs too long for one block are
+/// cut off and followed by a link to the full diff.
+#[derive(Debug)]
+pub(crate) struct Slack {
+    url: String,
+    link: Option<String>,
+    client: Client,
+}
+
+impl Slack {
+    pub(crate) fn new(config: &ChatWebhookConfig, client: Client) -> Self {
+        Slack {
+            url: config.url.clone(),
+            link: config.link.clone(),
+            client,
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for Slack {
+    async fn notify(&self, event: &ChangeEvent) -> anyhow::Result<()> {
+        let title = event.title.clone();
+        let header = chat::cut(&title, HEADER_LEN).0;
+
+        let mut intro = format!("<{}>", escape(&event.url));
+        if let Some(status) = event.status_change() {
+            intro += &format!("\nNew status '{}'", escape(&status));
+        }
+
+        let mut blocks = vec![
+            json!({
+                "type": "header",
+                "text": { "type": "plain_text", "text": header },
+            }),
+            json!({
+                "type": "section",
+                "text": { "type": "mrkdwn", "text": intro },
+            }),
+        ];
+
+        if let Some(body) = &event.body {
+            blocks.push(json!({
+                "type": "section",
+                "text": { "type": "mrkdwn", "text": chat::cut(&escape(body), SECTION_LEN).0 },
+            }));
+        } else if let Some(diff) = chat::diff(event) {
+            // Room for the code fence
+            let (diff, cut) = chat::cut(&escape(&diff), SECTION_LEN - 20);
+
+            blocks.push(json!({
+                "type": "section",
+                "text": { "type": "mrkdwn", "text": chat::code_block("", &diff) },
+            }));
+
+            if cut {
+                let footer = match chat::link(self.link.as_deref(), event) {
+                    Some(link) => format!("Diff cut off, <{}|see the full diff>", link),
+                    None => format!("Diff cut off, run `{}`", chat::history_hint(event)),
+                };
+
+                blocks.push(json!({
+                    "type": "context",
+                    "elements": [{ "type": "mrkdwn", "text": footer }],
+                }));
+            }
+        }
+
+        self.post(json!({ "text": title, "blocks": blocks })).await
+    }
+
+    async fn notify_digest(&self, digest: &Digest) -> anyhow::Result<()> {
+        let title = digest.title();
+        let header = chat::cut(&title, HEADER_LEN).0;
+        let text = chat::digest_block(&escape(&digest.render_text()), SECTION_LEN);
+
+        let blocks = vec![
+            json!({
+                "type": "header",
+                "text": { "type": "plain_text", "text": header },
+            }),
+            json!({
+                "type": "section",
+                "text": { "type": "mrkdwn", "text": text },
+            }),
+        ];
+
+        self.post(json!({ "text": title, "blocks": blocks })).await
+    }
+}
+
+impl Slack {
+    async fn post(&self, payload: serde_json::Value) -> anyhow::Result<()> {
+        let response = self
+            .client
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .body(payload.to_string())
+            .send()
+            .await
+            .context("Failed to call the Slack webhook")?;
+
+        check_response("Slack", response).await?;
+
+        Ok(())
+    }
+}
+
+/// Escape the characters Slack uses for links and mentions
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,23 @@
+use crate::notify::{digest::Digest, ChangeEvent, Notifier};
+use async_trait::async_trait;
+
+/// Prints every change and digest, for running in a terminal or under a process manager
+#[derive(Debug)]
+pub(crate) struct Stdout;
+
+#[async_trait]
+impl Notifier for Stdout {
+    async fn notify(&self, event: &ChangeEvent) -> anyhow::Result<()> {
+        println!("{}\n{}\n", event.title, event.description());
+        Ok(())
+    }
+
+    async fn notify_digest(&self, digest: &Digest) -> anyhow::Result<()> {
+        println!("{}\n{}", digest.title(), digest.render_text());
+        Ok(())
+    }
+
+    fn retries(&self) -> u32 {
+        0
+    }
+}
@@ -0,0 +1,125 @@
+use crate::config::NotifierConfig;
+use chrono::{DateTime, Duration as ChronoDuration, Local, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Quiet hours and rate limit of one notifier, telling the dispatcher when a
+/// notification has to be held back
+#[derive(Debug, Default)]
+pub(crate) struct Throttle {
+    quiet_hours: Option<QuietHours>,
+    limit: Option<(usize, Duration)>,
+    /// Deliveries within the last rate limit period
+    sent: VecDeque<Instant>,
+}
+
+#[derive(Debug)]
+struct QuietHours {
+    start: NaiveTime,
+    end: NaiveTime,
+    /// `None` for the system time zone
+    timezone: Option<Tz>,
+}
+
+impl Throttle {
+    pub(crate) fn new(config: &NotifierConfig) -> anyhow::Result<Self> {
+        let quiet_hours = match &config.quiet_hours {
+            Some(quiet_hours) => {
+                let (start, end) = quiet_hours.times()?;
+                Some(QuietHours {
+                    start,
+                    end,
+                    timezone: quiet_hours.timezone()?,
+                })
+            }
+            None => None,
+        };
+
+        Ok(Throttle {
+            quiet_hours,
+            limit: config
+                .rate_limit
+                .as_ref()
+                .map(|limit| (limit.max as usize, Duration::from_secs(limit.period))),
+            sent: VecDeque::new(),
+        })
+    }
+
+    /// How long a notification has to wait, `None` if it can go out now
+    pub(crate) fn hold(&mut self) -> Option<Duration> {
+        if let Some(until) = self
+            .quiet_hours
+            .as_ref()
+            .and_then(|quiet| quiet.until(Utc::now()))
+        {
+            return Some((until - Utc::now()).to_std().unwrap_or_default());
+        }
+
+        let (max, period) = self.limit?;
+        let now = Instant::now();
+
+        while let Some(sent) = self.sent.front() {
+            if now.duration_since(*sent) >= period {
+                self.sent.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        if self.sent.len() < max {
+            return None;
+        }
+
+        self.sent
+            .front()
+            .map(|oldest| period.saturating_sub(now.duration_since(*oldest)))
+    }
+
+    /// Count a delivery against the rate limit
+    pub(crate) fn record(&mut self) {
+        if self.limit.is_some() {
+            self.sent.push_back(Instant::now());
+        }
+    }
+}
+
+impl QuietHours {
+    /// The end of the quiet hours `now` falls into
+    fn until(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match &self.timezone {
+            Some(timezone) => self.until_in(timezone, now),
+            None => self.until_in(&Local, now),
+        }
+    }
+
+    fn until_in<Z: TimeZone>(&self, timezone: &Z, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let local = now.with_timezone(timezone).naive_local();
+        let time = local.time();
+
+        let quiet = if self.start < self.end {
+            time >= self.start && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        };
+        if !quiet {
+            return None;
+        }
+
+        let mut end = local.date().and_time(self.end);
+        if time >= self.end {
+            end += ChronoDuration::days(1);
+        }
+
+        // An end skipped by a DST change falls back to an hour later
+        let end = timezone.from_local_datetime(&end).earliest().or_else(|| {
+            timezone
+                .from_local_datetime(&(end + ChronoDuration::hours(1)))
+                .earliest()
+        })?;
+
+        Some(end.with_timezone(&Utc))
+    }
+}
@@ -0,0 +1,144 @@
+use crate::{
+    config::WebhookConfig,
+    notify::{check_response, digest::Digest, ChangeEvent, Notifier},
+};
+use anyhow::Context;
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use reqwest::{header::HeaderMap, Client};
+use sha2::Sha256;
+use std::time::Duration;
+
+/// Header carrying the hex encoded HMAC-SHA256 of the request body
+const SIGNATURE_HEADER: &str = "X-Signature-256";
+
+/// POSTs every `ChangeEvent` and `Digest` as JSON to a configured url.
+///
+/// `4xx` responses other than `429` are not retried.
+#[derive(Debug)]
+pub(crate) struct Webhook {
+    url: String,
+    headers: HeaderMap,
+    secret: Option<String>,
+    timeout: Duration,
+    retries: u32,
+    client: Client,
+}
+
+impl Webhook {
+    pub(crate) fn new(config: &WebhookConfig, client: Client) -> anyhow::Result<Self> {
+        Ok(Webhook {
+            url: config.url.clone(),
+            headers: config.header_map()?,
+            secret: config.secret.clone(),
+            timeout: Duration::from_secs(config.timeout),
+            retries: config.retries,
+            client,
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for Webhook {
+    async fn notify(&self, event: &ChangeEvent) -> anyhow::Result<()> {
+        self.post(serde_json::to_vec(event)?).await
+    }
+
+    async fn notify_digest(&self, digest: &Digest) -> anyhow::Result<()> {
+        self.post(serde_json::to_vec(digest)?).await
+    }
+
+    fn retries(&self) -> u32 {
+        self.retries
+    }
+}
+
+impl Webhook {
+    async fn post(&self, body: Vec<u8>) -> anyhow::Result<()> {
+        let mut request = self
+            .client
+            .post(&self.url)
+            .timeout(self.timeout)
+            .header("Content-Type", "application/json")
+            .headers(self.headers.clone());
+
+        if let Some(secret) = &self.secret {
+            request = request.header(SIGNATURE_HEADER, format!("sha256={}", sign(secret, &body)));
+        }
+
+        let response = request
+            .body(body)
+            .send()
+            .await
+            .with_context(|| format!("Failed to call webhook {}", self.url))?;
+
+        check_response(&format!("Webhook {}", self.url), response).await?;
+
+        Ok(())
+    }
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        notify::Permanent,
+        test_util::{event, serve},
+    };
+    use std::collections::BTreeMap;
+
+    fn webhook(url: String) -> Webhook {
+        let config = WebhookConfig {
+            url: url + "/hook",
+            headers: BTreeMap::from([("X-Token".to_string(), "abc".to_string())]),
+            secret: Some("secret".to_string()),
+            timeout: 5,
+            retries: 0,
+        };
+
+        Webhook::new(&config, Client::new()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn posts_signed_json() {
+        let (url, server) = serve(vec![204], "").await;
+
+        webhook(url).notify(&event("news")).await.unwrap();
+
+        let requests = server.await.unwrap();
+        let (head, body) = &requests[0];
+        let head = head.to_lowercase();
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+
+        assert!(head.starts_with("post /hook "));
+        assert!(head.contains("content-type: application/json\r\n"));
+        assert!(head.contains("x-token: abc\r\n"));
+        assert!(head.contains(&format!(
+            "x-signature-256: sha256={}\r\n",
+            sign("secret", body.as_bytes())
+        )));
+        assert_eq!(json["site"], "news");
+        assert_eq!(json["kind"], "change");
+        assert_eq!(json["new_snapshot"], 2);
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_permanent() {
+        let (url, server) = serve(vec![400, 500], "nope").await;
+        let webhook = webhook(url);
+
+        let bad_request = webhook.notify(&event("news")).await.unwrap_err();
+        let server_error = webhook.notify(&event("news")).await.unwrap_err();
+        server.await.unwrap();
+
+        assert!(bad_request.is::<Permanent>());
+        assert!(!server_error.is::<Permanent>());
+    }
+}
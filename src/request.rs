@@ -0,0 +1,234 @@
+//! The request a site is checked with
+
+use crate::config::SiteConfig;
+use anyhow::{bail, Context};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_TYPE, COOKIE, USER_AGENT},
+    Client, Method, RequestBuilder,
+};
+
+/// Sent unless a site sets its own
+pub(crate) const DEFAULT_USER_AGENT: &str = "Site Checker";
+
+/// Method, headers, query and body of the requests of one site
+#[derive(Clone, Debug)]
+pub(crate) struct SiteRequest {
+    method: Method,
+    /// Site headers with the cookies and user agent
+    headers: HeaderMap,
+    query: Vec<(String, String)>,
+    body: Option<Body>,
+}
+
+#[derive(Clone, Debug)]
+enum Body {
+    Form(Vec<(String, String)>),
+    Json(Vec<u8>),
+}
+
+impl SiteRequest {
+    pub(crate) fn new(site: &SiteConfig) -> anyhow::Result<Self> {
+        let mut headers = site.header_map()?;
+
+        if !site.cookies.is_empty() {
+            if headers.contains_key(COOKIE) {
+                bail!("Cookies must be set either in cookies or in the Cookie header");
+            }
+
+            let cookies = site
+                .cookies
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<_>>()
+                .join("; ");
+            headers.insert(
+                COOKIE,
+                HeaderValue::from_str(&cookies).context("Invalid cookies")?,
+            );
+        }
+
+        if let Some(user_agent) = &site.user_agent {
+            headers.insert(
+                USER_AGENT,
+                HeaderValue::from_str(user_agent).context("Invalid user agent")?,
+            );
+        }
+
+        let body = match (&site.form, &site.json) {
+            (Some(_), Some(_)) => bail!("A site can have either a form or a JSON body"),
+            (Some(form), None) => Some(Body::Form(
+                form.iter()
+                    .map(|(name, value)| (name.clone(), value.clone()))
+                    .collect(),
+            )),
+            (None, Some(json)) => Some(Body::Json(
+                serde_json::to_vec(json).context("Invalid JSON body")?,
+            )),
+            (None, None) => None,
+        };
+
+        Ok(SiteRequest {
+            method: site.method.method(),
+            headers,
+            query: site
+                .query
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+            body,
+        })
+    }
+
+    pub(crate) fn user_agent(&self) -> &str {
+        self.headers
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or(DEFAULT_USER_AGENT)
+    }
+
+    /// Whether the request can be made conditional, only reads can
+    pub(crate) fn is_safe(&self) -> bool {
+        matches!(self.method, Method::GET | Method::HEAD)
+    }
+
+    /// The request for `url`, HTML is accepted unless the site's headers say
+    /// otherwise. The `session` cookies of a login are added to the site's.
+    pub(crate) fn build(
+        &self,
+        client: &Client,
+        url: &str,
+        session: Option<&str>,
+    ) -> RequestBuilder {
+        let mut headers = self.headers.clone();
+
+        if let Some(session) = session {
+            let cookies = match headers.get(COOKIE).and_then(|value| value.to_str().ok()) {
+                Some(cookies) => format!("{}; {}", cookies, session),
+                None => session.to_string(),
+            };
+            if let Ok(value) = HeaderValue::from_str(&cookies) {
+                headers.insert(COOKIE, value);
+            }
+        }
+
+        let mut request = client
+            .request(self.method.clone(), url)
+            .header(ACCEPT, "text/html")
+            .headers(headers);
+
+        if !self.query.is_empty() {
+            request = request.query(&self.query);
+        }
+
+        match &self.body {
+            Some(Body::Form(form)) => request.form(form),
+            Some(Body::Json(json)) if self.headers.contains_key(CONTENT_TYPE) => {
+                request.body(json.clone())
+            }
+            Some(Body::Json(json)) => request
+                .header(CONTENT_TYPE, "application/json")
+                .body(json.clone()),
+            None => request,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::site;
+
+    fn build(toml: &str, session: Option<&str>) -> reqwest::Request {
+        let request = SiteRequest::new(&site("http://example.com/search?lang=de", toml)).unwrap();
+        request
+            .build(&Client::new(), "http://example.com/search?lang=de", session)
+            .build()
+            .unwrap()
+    }
+
+    fn body(request: &reqwest::Request) -> &str {
+        std::str::from_utf8(request.body().unwrap().as_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn plain_get_accepts_html() {
+        let request = build("", None);
+
+        assert_eq!(request.method(), Method::GET);
+        assert_eq!(request.url().as_str(), "http://example.com/search?lang=de");
+        assert_eq!(request.headers()[ACCEPT], "text/html");
+        assert!(request.headers().get(COOKIE).is_none());
+        assert!(request.body().is_none());
+
+        let site = SiteRequest::new(&site("http://example.com/", "")).unwrap();
+        assert_eq!(site.user_agent(), DEFAULT_USER_AGENT);
+        assert!(site.is_safe());
+    }
+
+    #[test]
+    fn headers_cookies_query_and_session() {
+        let request = build(
+            "user_agent = \"Bot/1.0\"\n\
+             headers = { Accept = \"application/json\", Accept-Language = \"de-CH\" }\n\
+             cookies = { consent = \"yes\", region = \"zh\" }\n\
+             query = { q = \"rust & tokio\", page = \"2\" }",
+            Some("session=abc"),
+        );
+
+        assert_eq!(request.headers()[ACCEPT], "application/json");
+        assert_eq!(request.headers()["accept-language"], "de-CH");
+        assert_eq!(request.headers()[USER_AGENT], "Bot/1.0");
+        assert_eq!(
+            request.headers()[COOKIE],
+            "consent=yes; region=zh; session=abc"
+        );
+        assert_eq!(
+            request.url().query(),
+            Some("lang=de&page=2&q=rust+%26+tokio")
+        );
+    }
+
+    #[test]
+    fn form_and_json_bodies() {
+        let form = build("method = \"POST\"\nform = { q = \"a b\", n = \"1\" }", None);
+        assert_eq!(form.method(), Method::POST);
+        assert_eq!(
+            form.headers()[CONTENT_TYPE],
+            "application/x-www-form-urlencoded"
+        );
+        assert_eq!(body(&form), "n=1&q=a+b");
+
+        let json = build("method = \"PUT\"\njson = { ids = [1, 2] }", None);
+        assert_eq!(json.method(), Method::PUT);
+        assert_eq!(json.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(body(&json), r#"{"ids":[1,2]}"#);
+
+        let typed = build(
+            "method = \"POST\"\njson = { a = 1 }\nheaders = { Content-Type = \"application/vnd.api+json\" }",
+            None,
+        );
+        assert_eq!(typed.headers()[CONTENT_TYPE], "application/vnd.api+json");
+
+        let site = SiteRequest::new(&site("http://example.com/", "method = \"POST\"")).unwrap();
+        assert!(!site.is_safe());
+    }
+
+    #[test]
+    fn conflicting_settings() {
+        let error = |toml: &str| {
+            SiteRequest::new(&site("http://example.com/", toml))
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(
+            error("form = { a = \"1\" }\njson = { a = 1 }"),
+            "A site can have either a form or a JSON body"
+        );
+        assert_eq!(
+            error("cookies = { a = \"1\" }\nheaders = { Cookie = \"b=2\" }"),
+            "Cookies must be set either in cookies or in the Cookie header"
+        );
+        assert_eq!(error("user_agent = \"Bot\\n\""), "Invalid user agent");
+    }
+}
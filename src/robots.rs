@@ -0,0 +1,221 @@
+//! Parsing robots.txt and matching paths against its rules
+
+use std::time::Duration;
+
+/// The groups of one robots.txt
+#[derive(Debug, Default)]
+pub(crate) struct Robots {
+    groups: Vec<Group>,
+}
+
+/// The rules for the user agents named at the start of a group
+#[derive(Debug, Default)]
+struct Group {
+    agents: Vec<String>,
+    rules: Vec<Rule>,
+    crawl_delay: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    allow: bool,
+    /// Path prefix, `*` matches any characters and a trailing `$` the end
+    pattern: String,
+}
+
+impl Robots {
+    pub(crate) fn parse(text: &str) -> Self {
+        let mut groups = Vec::new();
+        let mut group: Option<Group> = None;
+        // A group starts with one or more `User-agent` lines in a row
+        let mut agents = false;
+
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) => (key.trim().to_lowercase(), value.trim()),
+                None => continue,
+            };
+
+            match key.as_str() {
+                "user-agent" => {
+                    if !agents {
+                        groups.extend(group.take());
+                    }
+                    group
+                        .get_or_insert_with(Group::default)
+                        .agents
+                        .push(value.to_lowercase());
+                    agents = true;
+                }
+                "allow" | "disallow" => {
+                    agents = false;
+                    // An empty `Disallow` allows everything
+                    if let (Some(group), false) = (group.as_mut(), value.is_empty()) {
+                        group.rules.push(Rule {
+                            allow: key == "allow",
+                            pattern: value.to_string(),
+                        });
+                    }
+                }
+                "crawl-delay" => {
+                    agents = false;
+                    if let Some(group) = group.as_mut() {
+                        group.crawl_delay = value
+                            .parse::<f64>()
+                            .ok()
+                            .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+                            .map(Duration::from_secs_f64);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        groups.extend(group);
+
+        Robots { groups }
+    }
+
+    /// Whether `user_agent` may fetch `path`, including the query. The longest
+    /// matching rule decides, `Allow` wins a tie.
+    pub(crate) fn allows(&self, user_agent: &str, path: &str) -> bool {
+        let group = match self.group(user_agent) {
+            Some(group) => group,
+            None => return true,
+        };
+
+        group
+            .rules
+            .iter()
+            .filter(|rule| matches(&rule.pattern, path))
+            .max_by_key(|rule| (rule.pattern.len(), rule.allow))
+            .is_none_or(|rule| rule.allow)
+    }
+
+    pub(crate) fn crawl_delay(&self, user_agent: &str) -> Option<Duration> {
+        self.group(user_agent)?.crawl_delay
+    }
+
+    /// The group naming the longest part of `user_agent`, else the one for `*`
+    fn group(&self, user_agent: &str) -> Option<&Group> {
+        let user_agent = user_agent.to_lowercase();
+
+        self.groups
+            .iter()
+            .flat_map(|group| group.agents.iter().map(move |agent| (agent, group)))
+            .filter(|(agent, _)| {
+                !agent.is_empty() && *agent != "*" && user_agent.contains(agent.as_str())
+            })
+            .max_by_key(|(agent, _)| agent.len())
+            .map(|(_, group)| group)
+            .or_else(|| {
+                self.groups
+                    .iter()
+                    .find(|group| group.agents.iter().any(|agent| agent == "*"))
+            })
+    }
+}
+
+fn matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    let parts = pattern.split('*').collect::<Vec<_>>();
+
+    let mut rest = match path.strip_prefix(parts[0]) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    for (idx, part) in parts.iter().enumerate().skip(1) {
+        if anchored && idx == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+
+        match rest.find(part) {
+            Some(start) => rest = &rest[start + part.len()..],
+            None => return false,
+        }
+    }
+
+    !anchored || rest.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_prefixes_wildcards_and_end() {
+        assert!(matches("/private", "/private/page"));
+        assert!(!matches("/private", "/public"));
+        assert!(matches("/*.pdf", "/docs/file.pdf?download"));
+        assert!(matches("/*.pdf$", "/docs/file.pdf"));
+        assert!(!matches("/*.pdf$", "/docs/file.pdf?download"));
+        assert!(matches("/page$", "/page"));
+        assert!(!matches("/page$", "/pages"));
+        assert!(matches("/a*b*c", "/axxbyyc/more"));
+        assert!(!matches("/a*c*b", "/abc"));
+    }
+
+    #[test]
+    fn longest_rule_decides() {
+        let robots = Robots::parse(
+            "User-agent: *\n\
+             Disallow: /shop\n\
+             Allow: /shop/public\n\
+             Allow: /same\n\
+             Disallow: /same\n\
+             Disallow: /*.pdf$\n",
+        );
+
+        assert!(!robots.allows("Checker", "/shop/cart"));
+        assert!(robots.allows("Checker", "/shop/public/offers"));
+        assert!(robots.allows("Checker", "/same"));
+        assert!(!robots.allows("Checker", "/docs/a.pdf"));
+        assert!(robots.allows("Checker", "/docs/a.pdf?page=2"));
+        assert!(robots.allows("Checker", "/"));
+    }
+
+    #[test]
+    fn group_of_named_agent_else_star() {
+        let robots = Robots::parse(
+            "# comment\n\
+             User-agent: *\n\
+             Disallow: /\n\
+             Crawl-delay: 5\n\
+             \n\
+             User-agent: Site Checker\n\
+             User-agent: checker\n\
+             Disallow: /admin # not for us\n\
+             Crawl-delay: 0.5\n\
+             \n\
+             User-agent: site checker/2\n\
+             Disallow:\n",
+        );
+
+        assert!(robots.allows("Mozilla (compatible; checker)", "/page"));
+        assert!(!robots.allows("Mozilla (compatible; checker)", "/admin"));
+        assert_eq!(
+            robots.crawl_delay("Mozilla (compatible; checker)"),
+            Some(Duration::from_millis(500))
+        );
+
+        // The longest agent wins, its empty `Disallow` allows everything
+        assert!(robots.allows("Site Checker/2.1", "/admin"));
+        assert_eq!(robots.crawl_delay("Site Checker/2.1"), None);
+
+        assert!(!robots.allows("Other", "/page"));
+        assert_eq!(robots.crawl_delay("Other"), Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn empty_allows_everything() {
+        let robots = Robots::parse("");
+
+        assert!(robots.allows("Checker", "/anything"));
+        assert_eq!(robots.crawl_delay("Checker"), None);
+    }
+}
@@ -0,0 +1,349 @@
+//! Limiting the requests of all sites to one host
+
+use crate::{config::PolitenessConfig, robots::Robots};
+use anyhow::bail;
+use reqwest::{header::USER_AGENT, Client, RequestBuilder, Response, Url};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// How long a robots.txt is used before fetching it again
+const ROBOTS_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// How long a robots.txt that failed to load counts as allowing everything
+const ROBOTS_RETRY: Duration = Duration::from_secs(60 * 60);
+const ROBOTS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Hands out request slots per host, shared by all site actors, so pages of
+/// the same host are fetched one after the other instead of all at once
+#[derive(Clone, Debug)]
+pub(crate) struct Scheduler {
+    client: Client,
+    state: Arc<Mutex<State>>,
+}
+
+/// A slot for one request, freed when dropped
+#[derive(Debug)]
+pub(crate) struct Permit {
+    _slot: OwnedSemaphorePermit,
+    started: Instant,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    config: PolitenessConfig,
+    /// By `host:port`
+    hosts: HashMap<String, Host>,
+    /// By origin, kept across reconfigurations
+    robots: HashMap<String, CachedRobots>,
+}
+
+#[derive(Debug)]
+struct Host {
+    slots: Arc<Semaphore>,
+    max_concurrent: usize,
+    min_delay: Duration,
+    crawl_delay: Option<Duration>,
+    /// Earliest start of the next request
+    next: Instant,
+}
+
+#[derive(Debug)]
+struct CachedRobots {
+    robots: Arc<Robots>,
+    expires: Instant,
+}
+
+impl Scheduler {
+    pub(crate) fn new(client: Client) -> Self {
+        Scheduler {
+            client,
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    /// Apply new limits, requests already waiting finish with the old ones.
+    /// Hosts whose limits stay the same keep their slots and the
+    /// `Crawl-delay` of their robots.txt.
+    pub(crate) fn reconfigure(&self, config: PolitenessConfig) {
+        let mut state = self.state.lock().unwrap();
+        state.hosts.retain(|key, host| {
+            config.limits(host_name(key)) == (host.max_concurrent, host.min_delay)
+        });
+        state.config = config;
+    }
+
+    /// Wait until a request to the host of `url` may start
+    pub(crate) async fn acquire(&self, url: &Url) -> Permit {
+        let key = host_key(url);
+
+        let slots = self.state.lock().unwrap().host(&key).slots.clone();
+        let permit = slots
+            .acquire_owned()
+            .await
+            .expect("host semaphores are never closed");
+
+        let start = {
+            let mut state = self.state.lock().unwrap();
+            let host = state.host(&key);
+            let start = host.next.max(Instant::now());
+            host.next = start + host.min_delay.max(host.crawl_delay.unwrap_or_default());
+            start
+        };
+
+        tokio::time::sleep_until(start.into()).await;
+
+        Permit {
+            _slot: permit,
+            started: Instant::now(),
+        }
+    }
+
+    /// Send `request` once a request to its host may start, the permit is to
+    /// be kept until the response is read
+    pub(crate) async fn send(&self, request: RequestBuilder) -> anyhow::Result<(Response, Permit)> {
+        let (client, request) = request.build_split();
+        let request = request?;
+
+        let permit = self.acquire(request.url()).await;
+        let response = client.execute(request).await?;
+
+        Ok((response, permit))
+    }
+
+    /// Whether the robots.txt of the host of `url` allows `user_agent` to
+    /// fetch it, always true unless robots.txt is honored. `robots` overrides
+    /// the configured default.
+    pub(crate) async fn allows(&self, url: &Url, robots: Option<bool>, user_agent: &str) -> bool {
+        if !robots.unwrap_or_else(|| self.state.lock().unwrap().config.robots) {
+            return true;
+        }
+
+        let rules = self.robots(url, user_agent).await;
+
+        let crawl_delay = rules.crawl_delay(user_agent);
+        self.state.lock().unwrap().host(&host_key(url)).crawl_delay = crawl_delay;
+
+        let mut path = url.path().to_string();
+        if let Some(query) = url.query() {
+            path = format!("{}?{}", path, query);
+        }
+
+        rules.allows(user_agent, &path)
+    }
+
+    /// The cached robots.txt of the host of `url`, fetched as `user_agent` if
+    /// it expired
+    async fn robots(&self, url: &Url, user_agent: &str) -> Arc<Robots> {
+        let origin = url.origin().ascii_serialization();
+
+        if let Some(cached) = self.state.lock().unwrap().robots.get(&origin) {
+            if cached.expires > Instant::now() {
+                return cached.robots.clone();
+            }
+        }
+
+        let robots_url = format!("{}/robots.txt", origin);
+        let (robots, ttl) = match self.fetch(&robots_url, user_agent).await {
+            Ok(Some(text)) => (Robots::parse(&text), ROBOTS_TTL),
+            Ok(None) => (Robots::default(), ROBOTS_TTL),
+            Err(e) => {
+                log::warn!("Failed to fetch {}, allowing all: {:?}", robots_url, e);
+                (Robots::default(), ROBOTS_RETRY)
+            }
+        };
+
+        let robots = Arc::new(robots);
+        self.state.lock().unwrap().robots.insert(
+            origin,
+            CachedRobots {
+                robots: robots.clone(),
+                expires: Instant::now() + ttl,
+            },
+        );
+
+        robots
+    }
+
+    /// The robots.txt at `url`, `None` if there is none
+    async fn fetch(&self, url: &str, user_agent: &str) -> anyhow::Result<Option<String>> {
+        let request = self
+            .client
+            .get(url)
+            .header(USER_AGENT, user_agent)
+            .timeout(ROBOTS_TIMEOUT);
+        let (response, _permit) = self.send(request).await?;
+        let status = response.status();
+
+        if status.is_client_error() {
+            return Ok(None);
+        }
+        if !status.is_success() {
+            bail!("Responded with {}", status);
+        }
+
+        Ok(Some(response.text().await?))
+    }
+}
+
+impl Permit {
+    /// Time since the request could start, not counting the wait for it
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+impl State {
+    fn host(&mut self, key: &str) -> &mut Host {
+        let config = &self.config;
+
+        self.hosts.entry(key.to_string()).or_insert_with(|| {
+            let (max_concurrent, min_delay) = config.limits(host_name(key));
+
+            Host {
+                slots: Arc::new(Semaphore::new(max_concurrent)),
+                max_concurrent,
+                min_delay,
+                crawl_delay: None,
+                next: Instant::now(),
+            }
+        })
+    }
+}
+
+fn host_key(url: &Url) -> String {
+    format!(
+        "{}:{}",
+        url.host_str().unwrap_or_default(),
+        url.port_or_known_default().unwrap_or_default()
+    )
+}
+
+/// The host of a `host:port` key
+fn host_name(key: &str) -> &str {
+    key.rsplit_once(':').map_or(key, |(host, _)| host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::HostLimits, test_util::serve};
+
+    fn scheduler(max_concurrent: usize, min_delay: u64, robots: bool) -> Scheduler {
+        let scheduler = Scheduler::new(Client::new());
+        scheduler.reconfigure(PolitenessConfig {
+            max_concurrent,
+            min_delay,
+            robots,
+            ..Default::default()
+        });
+        scheduler
+    }
+
+    #[tokio::test]
+    async fn acquire_spaces_and_limits_requests() {
+        let scheduler = scheduler(1, 1, false);
+        let url = Url::parse("http://example.com/a").unwrap();
+        let start = Instant::now();
+
+        let first = scheduler.acquire(&url).await;
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        let waiting = tokio::spawn({
+            let scheduler = scheduler.clone();
+            let url = url.clone();
+            async move { scheduler.acquire(&url).await.started }
+        });
+
+        // The slot stays taken past the delay until the first permit is dropped
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(!waiting.is_finished());
+        drop(first);
+
+        let started = waiting.await.unwrap();
+        assert!(started - start >= Duration::from_millis(1500));
+
+        // Other hosts have their own slots
+        let other = Url::parse("http://example.com:8080/").unwrap();
+        let before = Instant::now();
+        let _other = scheduler.acquire(&other).await;
+        assert!(before.elapsed() < Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn acquire_waits_min_delay() {
+        let scheduler = scheduler(2, 1, false);
+        let url = Url::parse("http://example.com/").unwrap();
+
+        let start = Instant::now();
+        drop(scheduler.acquire(&url).await);
+        drop(scheduler.acquire(&url).await);
+
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn reload_keeps_unchanged_hosts() {
+        let scheduler = scheduler(1, 0, false);
+        let url = Url::parse("http://example.com/").unwrap();
+        let other = Url::parse("http://other.example/").unwrap();
+
+        for url in [&url, &other] {
+            scheduler
+                .state
+                .lock()
+                .unwrap()
+                .host(&host_key(url))
+                .crawl_delay = Some(Duration::from_secs(5));
+        }
+
+        let mut config = PolitenessConfig {
+            max_concurrent: 1,
+            min_delay: 0,
+            ..Default::default()
+        };
+        config.hosts.insert(
+            "other.example".to_string(),
+            HostLimits {
+                max_concurrent: Some(2),
+                min_delay: None,
+            },
+        );
+        scheduler.reconfigure(config);
+
+        let mut state = scheduler.state.lock().unwrap();
+        assert_eq!(
+            state.host(&host_key(&url)).crawl_delay,
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(state.host(&host_key(&other)).crawl_delay, None);
+        assert_eq!(state.host(&host_key(&other)).max_concurrent, 2);
+    }
+
+    #[tokio::test]
+    async fn robots_fetched_as_site() {
+        let (base, server) = serve(
+            vec![200],
+            "User-agent: checker\nDisallow: /private\nCrawl-delay: 0\n",
+        )
+        .await;
+        let scheduler = scheduler(1, 0, true);
+
+        let private = Url::parse(&format!("{}/private/page", base)).unwrap();
+        let public = Url::parse(&format!("{}/public", base)).unwrap();
+        assert!(!scheduler.allows(&private, None, "My Checker").await);
+        // Cached, the server only answers once
+        assert!(scheduler.allows(&public, None, "My Checker").await);
+        assert!(scheduler.allows(&private, Some(false), "My Checker").await);
+
+        let requests = server.await.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].0.starts_with("GET /robots.txt "));
+        assert!(requests[0]
+            .0
+            .to_lowercase()
+            .contains("user-agent: my checker\r\n"));
+    }
+}
@@ -0,0 +1,680 @@
+use crate::{
+    auth::{Auth, Secrets},
+    config::{InlineMode, SiteConfig},
+    diff::{ContentDiff, DiffHeader, OutputFormat},
+    error,
+    extract::Extractor,
+    fetch::{self, Backoff, FailureKind, FetchError},
+    health::{self, Health, HealthState, Transition},
+    history::{History, NewChange, NewFetch},
+    notify::{dispatch::Notifications, ChangeEvent, EventKind},
+    request::SiteRequest,
+    scheduler::Scheduler,
+    store::{unix_seconds, SnapshotStore},
+    template::Templates,
+};
+use bytes::Bytes;
+use chrono::{DateTime, Local, Utc};
+use reqwest::{
+    header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    Client, StatusCode, Url,
+};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+#[derive(Debug)]
+pub(crate) enum SiteMessage {
+    Check,
+    Reconfigure(Box<SiteConfig>, Box<SiteSetup>),
+}
+
+/// The parts of a site that can fail to build from its config, built before
+/// the actor gets them so a bad config leaves the running site untouched
+#[derive(Debug)]
+pub(crate) struct SiteSetup {
+    request: SiteRequest,
+    auth: Arc<Auth>,
+    extractor: Extractor,
+    templates: Option<Arc<Templates>>,
+}
+
+#[derive(Debug)]
+pub(crate) struct SiteState {
+    name: String,
+    slug: String,
+    href: String,
+    request: SiteRequest,
+    auth: Arc<Auth>,
+    /// Whether to honor robots.txt, the configured default if `None`
+    robots: Option<bool>,
+    scheduler: Scheduler,
+    timeout: Duration,
+    retries: u32,
+    retry_budget: Duration,
+    extractor: Extractor,
+    inline: InlineMode,
+    format: OutputFormat,
+    context: usize,
+    conditional: bool,
+    /// Body bytes not downloaded thanks to `304 Not Modified` responses
+    saved: u64,
+    health: Health,
+    notify: Vec<String>,
+    templates: Option<Arc<Templates>>,
+    notifications: Notifications,
+    client: Client,
+    store: SnapshotStore,
+    history: History,
+    loaded: bool,
+    result: Option<SiteResult>,
+}
+
+#[derive(Debug)]
+pub(crate) struct SiteResult {
+    pub(crate) status: StatusCode,
+    pub(crate) headers: HeaderMap,
+    pub(crate) fetched_at: SystemTime,
+    pub(crate) bytes: Bytes,
+    /// Id of the fetch in the history, if it was recorded
+    pub(crate) fetch_id: Option<i64>,
+}
+
+/// One response, read completely
+struct Fetched {
+    status: StatusCode,
+    headers: HeaderMap,
+    bytes: Bytes,
+    latency: Duration,
+}
+
+struct SiteResultDiff {
+    status: Option<StatusCode>,
+    diff: Option<ContentDiff>,
+}
+
+impl SiteSetup {
+    /// Credentials are only read when no `auth` of the site is given, reusing
+    /// one keeps its login session
+    pub(crate) fn new(
+        site: &SiteConfig,
+        secrets: &Secrets,
+        auth: Option<Arc<Auth>>,
+    ) -> anyhow::Result<Self> {
+        let auth = match auth {
+            Some(auth) => auth,
+            None => Arc::new(Auth::new(site, secrets)?),
+        };
+
+        Ok(SiteSetup {
+            request: SiteRequest::new(site)?,
+            auth,
+            extractor: Extractor::new(site)?,
+            templates: site.templates()?,
+        })
+    }
+
+    pub(crate) fn auth(&self) -> Arc<Auth> {
+        self.auth.clone()
+    }
+}
+
+impl SiteState {
+    pub(crate) fn new(
+        site: &SiteConfig,
+        setup: SiteSetup,
+        client: Client,
+        store: SnapshotStore,
+        history: History,
+        notifications: Notifications,
+        scheduler: Scheduler,
+    ) -> Self {
+        SiteState {
+            name: site.name.clone(),
+            slug: site.slug(),
+            href: site.url.clone(),
+            request: setup.request,
+            auth: setup.auth,
+            robots: site.robots,
+            scheduler,
+            timeout: Duration::from_secs(site.timeout),
+            retries: site.retries,
+            retry_budget: Duration::from_secs(site.retry_budget),
+            extractor: setup.extractor,
+            inline: site.inline,
+            format: site.format,
+            context: site.context,
+            conditional: site.conditional,
+            saved: 0,
+            health: Health::new(site.down_after),
+            notify: site.notify.clone(),
+            templates: setup.templates,
+            notifications,
+            client,
+            store,
+            history,
+            loaded: false,
+            result: None,
+        }
+    }
+
+    async fn load(&mut self) {
+        self.loaded = true;
+
+        match self.store.load(&self.slug, &self.href).await {
+            Ok(Some(result)) => {
+                log::info!(
+                    "Loaded previous result for {}, status: {}",
+                    self.name,
+                    result.status
+                );
+                self.result = Some(result);
+            }
+            Ok(None) => (),
+            Err(e) => log::warn!("Ignoring stored result for {}: {:?}", self.name, e),
+        }
+    }
+
+    fn reconfigure(&mut self, site: SiteConfig, setup: SiteSetup) {
+        self.request = setup.request;
+        self.auth = setup.auth;
+        self.robots = site.robots;
+        self.timeout = Duration::from_secs(site.timeout);
+        self.retries = site.retries;
+        self.retry_budget = Duration::from_secs(site.retry_budget);
+        self.extractor = setup.extractor;
+        self.inline = site.inline;
+        self.format = site.format;
+        self.context = site.context;
+        self.conditional = site.conditional;
+        self.health.set_down_after(site.down_after);
+        self.templates = setup.templates;
+        self.notify = site.notify;
+
+        if self.href != site.url {
+            log::info!(
+                "Url for {} changed from {} to {}, dropping previous result",
+                self.name,
+                self.href,
+                site.url
+            );
+            self.href = site.url;
+            self.result = None;
+            self.health = Health::new(site.down_after);
+        }
+    }
+
+    async fn check(&mut self) -> anyhow::Result<()> {
+        if !self.loaded {
+            self.load().await;
+        }
+
+        log::info!("Checking {}", self.name);
+
+        let url = Url::parse(&self.href)?;
+        if !self
+            .scheduler
+            .allows(&url, self.robots, self.request.user_agent())
+            .await
+        {
+            log::warn!("Skipping {}, disallowed by robots.txt", self.name);
+            return Ok(());
+        }
+
+        let conditional = self
+            .result
+            .as_ref()
+            .filter(|_| self.conditional && self.request.is_safe());
+        let fetched = match self.fetch(conditional).await {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                log::warn!("Failed to fetch {}: {}", self.name, error::describe(&e));
+                self.failed(e.to_string(), None).await;
+                return Ok(());
+            }
+        };
+
+        if FailureKind::of_status(fetched.status).is_some() {
+            self.failed(fetched.status.to_string(), Some(fetched)).await;
+            return Ok(());
+        }
+
+        let Fetched {
+            status,
+            headers,
+            bytes,
+            latency,
+        } = fetched;
+
+        if status == StatusCode::NOT_MODIFIED && conditional.is_some() {
+            self.not_modified(headers, latency).await;
+            return Ok(());
+        }
+
+        let fetched_at = SystemTime::now();
+        self.succeeded(status, fetched_at);
+
+        let fetch_id = self
+            .history
+            .record_fetch(NewFetch {
+                site: &self.name,
+                url: &self.href,
+                fetched_at: unix_seconds(fetched_at) as i64,
+                status: status.as_u16(),
+                latency,
+                body: &bytes,
+            })
+            .await;
+        let fetch_id = match fetch_id {
+            Ok(id) => Some(id),
+            Err(e) => {
+                log::error!("Failed to record fetch of {}: {:?}", self.name, e);
+                None
+            }
+        };
+
+        let new_result = SiteResult {
+            status,
+            headers,
+            fetched_at,
+            bytes,
+            fetch_id,
+        };
+
+        if let Err(e) = self.store.save(&self.slug, &self.href, &new_result).await {
+            log::error!("Failed to store result for {}: {:?}", self.name, e);
+        }
+
+        let prev = self.result.take();
+
+        let diff = prev
+            .as_ref()
+            .map(|result| result.diff(&new_result, &self.extractor, self.inline));
+
+        if let (Some(prev), Some(diff)) = (prev, diff) {
+            if diff.is_different() {
+                let header = DiffHeader {
+                    site: &self.name,
+                    old_time: unix_seconds(prev.fetched_at) as i64,
+                    new_time: unix_seconds(new_result.fetched_at) as i64,
+                    context: self.context,
+                };
+                let rendered = diff
+                    .diff
+                    .as_ref()
+                    .map(|diff| diff.render(self.format, &header));
+
+                let mut change_id = None;
+
+                if let Some(fetch_id) = fetch_id {
+                    let res = self
+                        .history
+                        .record_change(NewChange {
+                            fetch_id,
+                            old_fetch_id: prev.fetch_id,
+                            site: &self.name,
+                            detected_at: unix_seconds(new_result.fetched_at) as i64,
+                            old_status: prev.status.as_u16(),
+                            new_status: status.as_u16(),
+                            diff: rendered.as_deref(),
+                        })
+                        .await;
+
+                    match res {
+                        Ok(id) => change_id = Some(id),
+                        Err(e) => {
+                            log::error!("Failed to record change of {}: {:?}", self.name, e)
+                        }
+                    }
+                }
+
+                let event = ChangeEvent {
+                    kind: EventKind::Change,
+                    site: self.name.clone(),
+                    url: self.href.clone(),
+                    title: format!("{} Updated", self.name),
+                    body: None,
+                    old_status: prev.status.as_u16(),
+                    new_status: status.as_u16(),
+                    diff: rendered,
+                    old_fetched_at: prev.fetched_at.into(),
+                    new_fetched_at: new_result.fetched_at.into(),
+                    old_snapshot: prev.fetch_id,
+                    new_snapshot: fetch_id,
+                    change_id,
+                    error: None,
+                    down_since: None,
+                    outage: None,
+                    changes: diff.diff.map(Arc::new),
+                    context: self.context,
+                    old_body: prev.bytes.clone(),
+                    new_body: new_result.bytes.clone(),
+                    templates: self.templates.clone(),
+                };
+
+                log::info!("{}", event.title);
+                log::info!("{}", event.description());
+                self.notifications.send(&self.notify, event);
+            }
+        } else {
+            log::info!(
+                "First check for {}, status: {}",
+                self.name,
+                new_result.status
+            );
+        }
+
+        self.result = Some(new_result);
+        Ok(())
+    }
+
+    /// Fetch the site, retrying transient failures with jittered exponential
+    /// backoff. A response with an error status is returned once retrying
+    /// does not help, for `check` to count it as failed.
+    async fn fetch(&self, conditional: Option<&SiteResult>) -> Result<Fetched, FetchError> {
+        let mut backoff = Backoff::new(self.retries, self.retry_budget);
+
+        loop {
+            let started = Instant::now();
+            let (kind, retry_after, error) = match self.attempt(conditional).await {
+                Ok(fetched) => match FailureKind::of_status(fetched.status) {
+                    None => return Ok(fetched),
+                    Some(kind) => (kind, fetch::retry_after(&fetched.headers), Ok(fetched)),
+                },
+                Err(e) => (FailureKind::of(&e), None, Err(e)),
+            };
+
+            let attempt = backoff.attempts();
+            let delay = Some(kind)
+                .filter(|kind| kind.is_transient())
+                .and_then(|_| backoff.next(retry_after));
+
+            match (delay, error) {
+                (Some(delay), error) => {
+                    let reason = match &error {
+                        Ok(fetched) => fetched.status.to_string(),
+                        Err(e) => error::describe(e.as_ref()),
+                    };
+                    log::warn!(
+                        "Attempt {} to fetch {} failed, {}, retrying in {:?}: {}",
+                        attempt,
+                        self.name,
+                        kind,
+                        delay,
+                        reason
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                (None, Ok(fetched)) => {
+                    log::info!("{} still responds with {}", self.name, fetched.status);
+                    return Ok(Fetched {
+                        latency: started.elapsed(),
+                        ..fetched
+                    });
+                }
+                (None, Err(e)) => return Err(FetchError::new(kind, attempt, e)),
+            }
+        }
+    }
+
+    async fn attempt(&self, conditional: Option<&SiteResult>) -> anyhow::Result<Fetched> {
+        let build = |session: Option<&str>| {
+            let mut request = self
+                .request
+                .build(&self.client, &self.href, session)
+                .timeout(self.timeout);
+
+            if let Some(prev) = conditional {
+                if let Some(etag) = prev.headers.get(ETAG) {
+                    request = request.header(IF_NONE_MATCH, etag.clone());
+                }
+                if let Some(modified) = prev.headers.get(LAST_MODIFIED) {
+                    request = request.header(IF_MODIFIED_SINCE, modified.clone());
+                }
+            }
+
+            request
+        };
+
+        let (response, permit) = self.auth.send(&self.scheduler, build).await?;
+        let status = response.status();
+        let headers = response.headers().clone();
+        let bytes = response.bytes().await?;
+
+        Ok(Fetched {
+            status,
+            headers,
+            bytes,
+            latency: permit.elapsed(),
+        })
+    }
+
+    /// Count a failed check, a fetch error or a response with an error status
+    /// after all retries, towards the health of the site. The last good
+    /// result is kept to diff the first successful check against.
+    async fn failed(&mut self, error: String, response: Option<Fetched>) {
+        let failed_at = SystemTime::now();
+
+        let mut fetch_id = None;
+        if let Some(response) = &response {
+            let fetch = self
+                .history
+                .record_fetch(NewFetch {
+                    site: &self.name,
+                    url: &self.href,
+                    fetched_at: unix_seconds(failed_at) as i64,
+                    status: response.status.as_u16(),
+                    latency: response.latency,
+                    body: &response.bytes,
+                })
+                .await;
+            match fetch {
+                Ok(id) => fetch_id = Some(id),
+                Err(e) => log::error!("Failed to record fetch of {}: {:?}", self.name, e),
+            }
+        }
+
+        let since = match self.health.failure(failed_at) {
+            Some(Transition::WentDown { since }) => since,
+            _ if self.health.state() == HealthState::Down => {
+                log::warn!("{} is still down: {}", self.name, error);
+                return;
+            }
+            _ => {
+                log::warn!(
+                    "{} is degraded, check {} in a row failed: {}",
+                    self.name,
+                    self.health.failures(),
+                    error
+                );
+                return;
+            }
+        };
+
+        let body = match &response {
+            Some(response) => format!(
+                "Responding with '{}' since {}",
+                response.status,
+                local_time(since)
+            ),
+            None => format!("No response since {}: {}", local_time(since), error),
+        };
+        let prev = self.result.as_ref();
+
+        let event = ChangeEvent {
+            kind: EventKind::Down,
+            site: self.name.clone(),
+            url: self.href.clone(),
+            title: format!("{} Down", self.name),
+            body: Some(body),
+            old_status: prev.map_or(0, |prev| prev.status.as_u16()),
+            new_status: response
+                .as_ref()
+                .map_or(0, |response| response.status.as_u16()),
+            diff: None,
+            old_fetched_at: prev.map_or(since, |prev| prev.fetched_at).into(),
+            new_fetched_at: failed_at.into(),
+            old_snapshot: prev.and_then(|prev| prev.fetch_id),
+            new_snapshot: fetch_id,
+            change_id: None,
+            error: Some(error),
+            down_since: Some(since.into()),
+            outage: None,
+            changes: None,
+            context: self.context,
+            old_body: prev.map(|prev| prev.bytes.clone()).unwrap_or_default(),
+            new_body: response.map(|response| response.bytes).unwrap_or_default(),
+            templates: self.templates.clone(),
+        };
+
+        log::error!("{}", event.title);
+        log::error!("{}", event.description());
+        self.notifications.send(&self.notify, event);
+    }
+
+    /// Count a successful check towards the health of the site, telling about
+    /// the end of an outage
+    fn succeeded(&mut self, status: StatusCode, fetched_at: SystemTime) {
+        let (since, outage) = match self.health.success(fetched_at) {
+            Some(Transition::Recovered { since, outage }) => (since, outage),
+            _ => return,
+        };
+        let prev = self.result.as_ref();
+
+        let event = ChangeEvent {
+            kind: EventKind::Recovered,
+            site: self.name.clone(),
+            url: self.href.clone(),
+            title: format!("{} Recovered", self.name),
+            body: Some(format!(
+                "Back after {}, status '{}'",
+                health::format_duration(outage),
+                status
+            )),
+            old_status: prev.map_or(0, |prev| prev.status.as_u16()),
+            new_status: status.as_u16(),
+            diff: None,
+            old_fetched_at: prev.map_or(since, |prev| prev.fetched_at).into(),
+            new_fetched_at: fetched_at.into(),
+            old_snapshot: prev.and_then(|prev| prev.fetch_id),
+            new_snapshot: None,
+            change_id: None,
+            error: None,
+            down_since: Some(since.into()),
+            outage: Some(outage.as_secs()),
+            changes: None,
+            context: self.context,
+            old_body: Bytes::new(),
+            new_body: Bytes::new(),
+            templates: self.templates.clone(),
+        };
+
+        log::info!("{}", event.title);
+        log::info!("{}", event.description());
+        self.notifications.send(&self.notify, event);
+    }
+
+    /// Keep the last result on a `304 Not Modified`, only taking over its
+    /// updated validators and the id of the fetch recording it
+    async fn not_modified(&mut self, headers: HeaderMap, latency: Duration) {
+        if let Some(prev) = &self.result {
+            self.succeeded(prev.status, SystemTime::now());
+        }
+
+        let prev = match self.result.as_mut() {
+            Some(prev) => prev,
+            None => return,
+        };
+
+        let mut updated = false;
+        for name in [ETAG, LAST_MODIFIED] {
+            if let Some(value) = headers.get(&name) {
+                if prev.headers.get(&name) != Some(value) {
+                    prev.headers.insert(name, value.clone());
+                    updated = true;
+                }
+            }
+        }
+
+        self.saved += prev.bytes.len() as u64;
+        log::info!(
+            "{} not modified, saved {} bytes ({} since start)",
+            self.name,
+            prev.bytes.len(),
+            self.saved
+        );
+
+        let fetch = self
+            .history
+            .record_fetch(NewFetch {
+                site: &self.name,
+                url: &self.href,
+                fetched_at: unix_seconds(SystemTime::now()) as i64,
+                status: prev.status.as_u16(),
+                latency,
+                body: &prev.bytes,
+            })
+            .await;
+        match fetch {
+            // The same body, the newest fetch is the baseline for the next
+            // change and outlives pruning of the older ones
+            Ok(id) => {
+                prev.fetch_id = Some(id);
+                updated = true;
+            }
+            Err(e) => log::error!("Failed to record fetch of {}: {:?}", self.name, e),
+        }
+
+        if updated {
+            if let Err(e) = self.store.save(&self.slug, &self.href, prev).await {
+                log::error!("Failed to store result for {}: {:?}", self.name, e);
+            }
+        }
+    }
+
+    pub(crate) async fn handle_message(&mut self, message: SiteMessage) -> anyhow::Result<()> {
+        match message {
+            SiteMessage::Check => {
+                // Failing checks must not stop the actor
+                if let Err(e) = self.check().await {
+                    log::error!("Failed to check {}: {:?}", self.name, e);
+                }
+            }
+            SiteMessage::Reconfigure(site, setup) => self.reconfigure(*site, *setup),
+        }
+
+        Ok(())
+    }
+}
+
+impl SiteResult {
+    fn diff(&self, rhs: &SiteResult, extractor: &Extractor, inline: InlineMode) -> SiteResultDiff {
+        let status = if self.status != rhs.status {
+            Some(rhs.status)
+        } else {
+            None
+        };
+
+        let old = extractor.extract(&self.bytes);
+        let new = extractor.extract(&rhs.bytes);
+
+        let diff = ContentDiff::new(&old, &new, extractor.mode(), inline);
+
+        SiteResultDiff { status, diff }
+    }
+}
+
+impl SiteResultDiff {
+    fn is_different(&self) -> bool {
+        self.status.is_some() || self.diff.is_some()
+    }
+}
+
+/// `time` in local time, for outage messages
+fn local_time(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .with_timezone(&Local)
+        .format("%Y-%m-%d %H:%M")
+        .to_string()
+}
@@ -0,0 +1,182 @@
+use crate::site::SiteResult;
+use anyhow::Context;
+use bytes::Bytes;
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, ETAG, LAST_MODIFIED},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::io::AsyncWriteExt;
+
+/// Keeps the last result of every site on disk, one `<slug>.snapshot` file per
+/// site.
+///
+/// A snapshot file starts with a single line of JSON metadata, followed by the
+/// raw response body.
+#[derive(Clone, Debug)]
+pub(crate) struct SnapshotStore {
+    dir: PathBuf,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct SnapshotMeta {
+    url: String,
+    status: u16,
+    fetched_at: u64,
+    /// Only the validators for conditional requests, other headers such as
+    /// `Set-Cookie` may hold secrets
+    headers: Vec<(String, String)>,
+    #[serde(default)]
+    fetch_id: Option<i64>,
+}
+
+impl SnapshotStore {
+    pub(crate) fn new(dir: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let dir = dir.into();
+
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create snapshot dir {}", dir.display()))?;
+
+        Ok(SnapshotStore { dir })
+    }
+
+    pub(crate) fn path(&self, slug: &str) -> PathBuf {
+        self.dir.join(format!("{}.snapshot", slug))
+    }
+
+    /// Load the stored result for `slug`, if there is one and it was fetched
+    /// from `url`
+    pub(crate) async fn load(&self, slug: &str, url: &str) -> anyhow::Result<Option<SiteResult>> {
+        let path = self.path(slug);
+
+        let contents = match tokio::fs::read(&path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+
+        let split = contents
+            .iter()
+            .position(|b| *b == b'\n')
+            .with_context(|| format!("Missing metadata in {}", path.display()))?;
+
+        let meta: SnapshotMeta = serde_json::from_slice(&contents[..split])
+            .with_context(|| format!("Invalid metadata in {}", path.display()))?;
+
+        if meta.url != url {
+            return Ok(None);
+        }
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in meta.headers {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(&value),
+            ) {
+                headers.append(name, value);
+            }
+        }
+
+        Ok(Some(SiteResult {
+            status: StatusCode::from_u16(meta.status)?,
+            headers,
+            fetched_at: UNIX_EPOCH + Duration::from_secs(meta.fetched_at),
+            bytes: Bytes::copy_from_slice(&contents[split + 1..]),
+            fetch_id: meta.fetch_id,
+        }))
+    }
+
+    /// Store `result` for `slug`, replacing the previous snapshot atomically
+    pub(crate) async fn save(
+        &self,
+        slug: &str,
+        url: &str,
+        result: &SiteResult,
+    ) -> anyhow::Result<()> {
+        let meta = SnapshotMeta {
+            url: url.to_string(),
+            status: result.status.as_u16(),
+            fetched_at: unix_seconds(result.fetched_at),
+            headers: result
+                .headers
+                .iter()
+                .filter(|(name, _)| **name == ETAG || **name == LAST_MODIFIED)
+                .filter_map(|(name, value)| {
+                    Some((name.as_str().to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect(),
+            fetch_id: result.fetch_id,
+        };
+
+        let mut contents = serde_json::to_vec(&meta)?;
+        contents.push(b'\n');
+        contents.extend_from_slice(&result.bytes);
+
+        write_atomic(&self.path(slug), &contents).await
+    }
+}
+
+pub(crate) fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Write to a temporary file next to `path` and rename it into place, so
+/// readers see either the old or the new contents but never a partial write
+async fn write_atomic(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+
+    let mut file = tokio::fs::File::create(&tmp)
+        .await
+        .with_context(|| format!("Failed to create {}", tmp.display()))?;
+    file.write_all(contents).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    tokio::fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("Failed to move snapshot into {}", path.display()))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::SET_COOKIE;
+
+    #[tokio::test]
+    async fn saves_only_validators() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SnapshotStore::new(dir.path()).unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(ETAG, HeaderValue::from_static("\"v1\""));
+        headers.insert(SET_COOKIE, HeaderValue::from_static("session=secret"));
+        let result = SiteResult {
+            status: StatusCode::OK,
+            headers,
+            fetched_at: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            bytes: Bytes::from_static(b"<p>body</p>\n"),
+            fetch_id: Some(3),
+        };
+        store.save("site", "http://a/", &result).await.unwrap();
+
+        let contents = std::fs::read_to_string(store.path("site")).unwrap();
+        assert!(!contents.contains("secret"));
+
+        let loaded = store.load("site", "http://a/").await.unwrap().unwrap();
+        assert_eq!(loaded.headers.len(), 1);
+        assert_eq!(loaded.headers[ETAG], "\"v1\"");
+        assert_eq!(loaded.bytes, result.bytes);
+        assert_eq!(loaded.fetched_at, result.fetched_at);
+        assert_eq!(loaded.fetch_id, Some(3));
+
+        assert!(store.load("site", "http://b/").await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,235 @@
+use crate::{
+    auth::{Auth, Secrets},
+    config::{Config, NotifierConfig, PolitenessConfig, SiteConfig},
+    history::History,
+    notify::dispatch::Notifications,
+    scheduler::Scheduler,
+    site::{SiteMessage, SiteSetup, SiteState},
+    store::SnapshotStore,
+};
+use anyhow::bail;
+use reqwest::Client;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{task::JoinHandle, time::Instant};
+use tokio_actors::{ActorHandle, RootHandle};
+
+/// Owns one `SiteState` actor per configured site and keeps the set of running
+/// actors in line with the config it was last given.
+pub(crate) struct Supervisor {
+    client: Client,
+    store: SnapshotStore,
+    history: History,
+    notifications: Notifications,
+    secrets: Secrets,
+    scheduler: Scheduler,
+    dispatcher: JoinHandle<()>,
+    notifiers: BTreeMap<String, NotifierConfig>,
+    politeness: PolitenessConfig,
+    root: RootHandle,
+    sites: HashMap<String, RunningSite>,
+}
+
+struct RunningSite {
+    config: SiteConfig,
+    /// Shared with the actor, to be kept by reloads not changing it
+    auth: Arc<Auth>,
+    handle: ActorHandle<SiteMessage>,
+    ticker: JoinHandle<()>,
+}
+
+impl Supervisor {
+    pub(crate) fn new(
+        client: Client,
+        store: SnapshotStore,
+        history: History,
+        secrets: Secrets,
+    ) -> Self {
+        let (notifications, dispatcher) = Notifications::spawn(client.clone());
+        let scheduler = Scheduler::new(client.clone());
+
+        Supervisor {
+            client,
+            store,
+            history,
+            notifications,
+            secrets,
+            scheduler,
+            dispatcher,
+            notifiers: BTreeMap::new(),
+            politeness: PolitenessConfig::default(),
+            root: tokio_actors::root(),
+            sites: HashMap::new(),
+        }
+    }
+
+    /// Diff `config` against the running actors: spawn new sites, close removed
+    /// ones and reconfigure changed ones in place, keeping their last result.
+    /// A site that fails to start or reconfigure does not stop the others, it
+    /// keeps its current config and is named in the returned error.
+    pub(crate) async fn apply(&mut self, config: Config) -> anyhow::Result<()> {
+        if config.notifiers != self.notifiers {
+            log::info!("Configuring notifiers");
+            self.notifications.reconfigure(config.notifiers.clone());
+            self.notifiers = config.notifiers;
+        }
+
+        if config.politeness != self.politeness {
+            log::info!("Configuring host limits");
+            self.scheduler.reconfigure(config.politeness.clone());
+            self.politeness = config.politeness;
+        }
+
+        let mut sites: HashMap<String, SiteConfig> = config
+            .sites
+            .into_iter()
+            .map(|site| (site.name.clone(), site))
+            .collect();
+
+        let removed: Vec<String> = self
+            .sites
+            .keys()
+            .filter(|name| !sites.contains_key(*name))
+            .cloned()
+            .collect();
+
+        for name in removed {
+            if let Some(running) = self.sites.remove(&name) {
+                log::info!("Stopping {}", name);
+                running.ticker.abort();
+                running.handle.close().await;
+            }
+        }
+
+        let mut failed = Vec::new();
+
+        for (name, running) in self.sites.iter_mut() {
+            let site = match sites.remove(name) {
+                Some(site) => site,
+                None => continue,
+            };
+
+            if site == running.config {
+                continue;
+            }
+
+            log::info!("Reconfiguring {}", name);
+
+            if let Err(e) = running.reconfigure(site, &self.secrets).await {
+                log::error!("Keeping current config of {}: {:?}", name, e);
+                failed.push(name.clone());
+            }
+        }
+
+        for (name, site) in sites {
+            log::info!("Starting {}", name);
+            match self.spawn(site).await {
+                Ok(running) => {
+                    self.sites.insert(name, running);
+                }
+                Err(e) => {
+                    log::error!("Failed to start {}: {:?}", name, e);
+                    failed.push(name);
+                }
+            }
+        }
+
+        if !failed.is_empty() {
+            failed.sort();
+            bail!("Failed to apply the config of {}", failed.join(", "));
+        }
+
+        Ok(())
+    }
+
+    pub(crate) async fn close(self) {
+        for running in self.sites.values() {
+            running.ticker.abort();
+        }
+
+        self.root.close().await;
+        self.dispatcher.abort();
+    }
+
+    async fn spawn(&mut self, site: SiteConfig) -> anyhow::Result<RunningSite> {
+        let setup = SiteSetup::new(&site, &self.secrets, None)?;
+        let auth = setup.auth();
+        let state = SiteState::new(
+            &site,
+            setup,
+            self.client.clone(),
+            self.store.clone(),
+            self.history.clone(),
+            self.notifications.clone(),
+            self.scheduler.clone(),
+        );
+
+        let handle = self
+            .root
+            .spawn_child(state, move |state, msg, _| {
+                Box::pin(async move { state.handle_message(msg).await })
+            })
+            .await?;
+
+        let ticker = spawn_ticker(handle.clone(), Instant::now(), site.interval());
+
+        Ok(RunningSite {
+            config: site,
+            auth,
+            handle,
+            ticker,
+        })
+    }
+}
+
+impl RunningSite {
+    /// Hand `site` to the actor, only recording it once the actor has it
+    async fn reconfigure(&mut self, site: SiteConfig, secrets: &Secrets) -> anyhow::Result<()> {
+        let auth = Some(self.auth.clone()).filter(|_| self.config.same_login(&site));
+        let setup = SiteSetup::new(&site, secrets, auth)?;
+        let auth = setup.auth();
+
+        self.handle
+            .send(SiteMessage::Reconfigure(
+                Box::new(site.clone()),
+                Box::new(setup),
+            ))
+            .await?;
+
+        // The next check comes one new interval later instead of right away
+        if site.interval != self.config.interval {
+            self.ticker.abort();
+            self.ticker = spawn_ticker(
+                self.handle.clone(),
+                Instant::now() + site.interval(),
+                site.interval(),
+            );
+        }
+
+        self.config = site;
+        self.auth = auth;
+        Ok(())
+    }
+}
+
+/// Send a `Check` to the actor at `start` and then once every `period`
+fn spawn_ticker(
+    handle: ActorHandle<SiteMessage>,
+    start: Instant,
+    period: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval_at(start, period);
+
+        loop {
+            interval.tick().await;
+
+            if handle.send(SiteMessage::Check).await.is_err() {
+                break;
+            }
+        }
+    })
+}
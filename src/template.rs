@@ -0,0 +1,255 @@
+//! Notification titles and bodies rendered from user templates
+
+use crate::{
+    config::{DiffMode, InlineMode},
+    diff::{ContentDiff, OutputFormat},
+    error, health,
+    notify::{ChangeEvent, EventKind},
+};
+use anyhow::Context as _;
+use chrono::{Local, TimeZone, Utc};
+use std::{sync::Arc, time::Duration};
+use tera::{Context, Tera};
+
+const TITLE: &str = "title";
+const BODY: &str = "body";
+
+/// The compiled `title` and `body` templates of a site or notifier
+#[derive(Debug)]
+pub(crate) struct Templates {
+    tera: Tera,
+    title: bool,
+    body: bool,
+}
+
+impl Templates {
+    /// Compile the templates, `None` if neither is set
+    pub(crate) fn new(title: Option<&str>, body: Option<&str>) -> anyhow::Result<Option<Self>> {
+        if title.is_none() && body.is_none() {
+            return Ok(None);
+        }
+
+        let mut tera = Tera::default();
+
+        if let Some(title) = title {
+            tera.add_raw_template(TITLE, title)
+                .map_err(describe)
+                .context("Invalid title template")?;
+        }
+        if let Some(body) = body {
+            tera.add_raw_template(BODY, body)
+                .map_err(describe)
+                .context("Invalid body template")?;
+        }
+
+        Ok(Some(Templates {
+            tera,
+            title: title.is_some(),
+            body: body.is_some(),
+        }))
+    }
+
+    /// Compile the templates and render them for a made up change, so typos
+    /// in variable names are caught at load time
+    pub(crate) fn validate(title: Option<&str>, body: Option<&str>) -> anyhow::Result<()> {
+        let templates = match Templates::new(title, body)? {
+            Some(templates) => templates,
+            None => return Ok(()),
+        };
+
+        let context = context(&sample());
+
+        if templates.title {
+            templates
+                .tera
+                .render(TITLE, &context)
+                .map_err(describe)
+                .context("Invalid title template")?;
+        }
+        if templates.body {
+            templates
+                .tera
+                .render(BODY, &context)
+                .map_err(describe)
+                .context("Invalid body template")?;
+        }
+
+        Ok(())
+    }
+
+    fn render(&self, name: &str, event: &ChangeEvent) -> Option<String> {
+        let result = self.tera.render(name, &context(event)).map_err(describe);
+
+        match result {
+            Ok(rendered) => Some(rendered.trim().to_string()),
+            Err(e) => {
+                log::error!("Failed to render {} of {}: {:?}", name, event.site, e);
+                None
+            }
+        }
+    }
+}
+
+/// `event` with the title and body rendered from the templates of its site,
+/// or else of `notifier`
+pub(crate) fn apply(event: &Arc<ChangeEvent>, notifier: Option<&Templates>) -> Arc<ChangeEvent> {
+    let site = event.templates.as_deref();
+
+    let pick = |wanted: fn(&Templates) -> bool| {
+        site.filter(|templates| wanted(templates))
+            .or_else(|| notifier.filter(|templates| wanted(templates)))
+    };
+
+    let title = pick(|templates| templates.title);
+    let body = pick(|templates| templates.body);
+
+    if title.is_none() && body.is_none() {
+        return event.clone();
+    }
+
+    let mut rendered = (**event).clone();
+
+    if let Some(title) = title.and_then(|templates| templates.render(TITLE, event)) {
+        rendered.title = title;
+    }
+    if let Some(body) = body.and_then(|templates| templates.render(BODY, event)) {
+        rendered.body = Some(body);
+    }
+
+    Arc::new(rendered)
+}
+
+fn context(event: &ChangeEvent) -> Context {
+    let stats = event
+        .changes
+        .as_ref()
+        .map(|changes| changes.stats())
+        .unwrap_or_default();
+    let local = |time: &chrono::DateTime<Utc>| {
+        time.with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M")
+            .to_string()
+    };
+
+    let mut context = Context::new();
+    context.insert("kind", &event.kind);
+    context.insert("site", &event.site);
+    context.insert("url", &event.url);
+    context.insert("old_status", &event.old_status);
+    context.insert("new_status", &event.new_status);
+    context.insert("status", &event.status_change());
+    context.insert("status_changed", &(event.old_status != event.new_status));
+    context.insert("diff", &event.diff);
+    context.insert("text_diff", &event.render(OutputFormat::Text));
+    context.insert("unified_diff", &event.render(OutputFormat::Unified));
+    context.insert("added", &stats.added);
+    context.insert("removed", &stats.removed);
+    context.insert("old_snapshot", &event.old_snapshot);
+    context.insert("new_snapshot", &event.new_snapshot);
+    context.insert("change_id", &event.change_id);
+    context.insert(
+        "history_command",
+        &event
+            .change_id
+            .map(|id| format!("site-diff-checker_rs history diff {}", id)),
+    );
+    context.insert("old_fetched_at", &event.old_fetched_at.to_rfc3339());
+    context.insert("new_fetched_at", &event.new_fetched_at.to_rfc3339());
+    context.insert("old_time", &local(&event.old_fetched_at));
+    context.insert("new_time", &local(&event.new_fetched_at));
+    context.insert("error", &event.error);
+    context.insert("down_since", &event.down_since.as_ref().map(local));
+    context.insert(
+        "outage",
+        &event
+            .outage
+            .map(|outage| health::format_duration(Duration::from_secs(outage))),
+    );
+    context
+}
+
+/// A change with every variable set, for validating templates
+fn sample() -> ChangeEvent {
+    let (old, new) = ("Example\n", "Example\nDomain\n");
+
+    ChangeEvent {
+        kind: EventKind::Change,
+        site: "Example".to_string(),
+        url: "https://example.com/".to_string(),
+        title: "Example Updated".to_string(),
+        body: None,
+        old_status: 200,
+        new_status: 503,
+        diff: Some("Added:\nExample".to_string()),
+        old_fetched_at: Utc.timestamp_opt(0, 0).unwrap(),
+        new_fetched_at: Utc.timestamp_opt(1800, 0).unwrap(),
+        old_snapshot: Some(1),
+        new_snapshot: Some(2),
+        change_id: Some(1),
+        error: Some("timed out after 3 attempts".to_string()),
+        down_since: Some(Utc.timestamp_opt(600, 0).unwrap()),
+        outage: Some(1200),
+        changes: ContentDiff::new(old, new, DiffMode::Text, InlineMode::Word).map(Arc::new),
+        context: 3,
+        old_body: old.into(),
+        new_body: new.into(),
+        templates: None,
+    }
+}
+
+/// Tera keeps the actual problem in the source of its errors
+fn describe(error: tera::Error) -> anyhow::Error {
+    anyhow::anyhow!(error::describe(&error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::event;
+
+    fn templates(title: Option<&str>, body: Option<&str>) -> Templates {
+        Templates::new(title, body).unwrap().unwrap()
+    }
+
+    #[test]
+    fn validates_against_a_full_change() {
+        assert!(Templates::validate(None, None).is_ok());
+        assert!(Templates::validate(
+            Some("{{ site }}: +{{ added }} -{{ removed }}"),
+            Some("{{ unified_diff | truncate(length=200) }}\n{{ text_diff }}"),
+        )
+        .is_ok());
+
+        let error = Templates::validate(Some("{{ sitename }}"), None).unwrap_err();
+        assert!(format!("{:#}", error).contains("title"));
+        assert!(Templates::validate(None, Some("{% if site %}")).is_err());
+    }
+
+    #[test]
+    fn site_templates_take_precedence() {
+        let notifier = templates(Some("notifier {{ site }}"), Some("notifier body"));
+
+        let plain = Arc::new(event("news"));
+        let rendered = apply(&plain, Some(&notifier));
+        assert_eq!(rendered.title, "notifier news");
+        assert_eq!(rendered.body.as_deref(), Some("notifier body"));
+
+        let mut own = event("news");
+        own.templates = Some(Arc::new(templates(Some("site {{ site }}"), None)));
+        let rendered = apply(&Arc::new(own), Some(&notifier));
+        assert_eq!(rendered.title, "site news");
+        assert_eq!(rendered.body.as_deref(), Some("notifier body"));
+
+        let unchanged = apply(&plain, None);
+        assert!(Arc::ptr_eq(&unchanged, &plain));
+    }
+
+    #[test]
+    fn failed_render_keeps_the_default() {
+        // Valid for the sample, but the event has no error
+        let notifier = templates(Some("{{ error | upper }}"), None);
+
+        let rendered = apply(&Arc::new(event("news")), Some(&notifier));
+        assert_eq!(rendered.title, "news Updated");
+    }
+}
@@ -0,0 +1,129 @@
+//! Fixtures shared by the tests of several modules
+
+use crate::{
+    config::SiteConfig,
+    notify::{ChangeEvent, EventKind},
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+    task::JoinHandle,
+};
+
+/// A site at `url` with the settings in `toml` added
+pub(crate) fn site(url: &str, toml: &str) -> SiteConfig {
+    toml::from_str(&format!("name = \"Example\"\nurl = \"{}\"\n{}", url, toml)).unwrap()
+}
+
+/// A change of `site` from `a` to `b`
+pub(crate) fn event(site: &str) -> ChangeEvent {
+    let at = DateTime::<Utc>::from_timestamp(1_700_000_000, 0).unwrap();
+
+    ChangeEvent {
+        kind: EventKind::Change,
+        site: site.to_string(),
+        url: format!("http://{}.example/", site),
+        title: format!("{} Updated", site),
+        body: None,
+        old_status: 200,
+        new_status: 200,
+        diff: Some("Removed: a\nAdded: b".to_string()),
+        old_fetched_at: at,
+        new_fetched_at: at,
+        old_snapshot: Some(1),
+        new_snapshot: Some(2),
+        change_id: Some(1),
+        error: None,
+        down_since: None,
+        outage: None,
+        changes: None,
+        context: 3,
+        old_body: Bytes::from_static(b"a"),
+        new_body: Bytes::from_static(b"b"),
+        templates: None,
+    }
+}
+
+/// One response of `serve_replies`
+pub(crate) struct Reply {
+    pub(crate) status: u16,
+    pub(crate) headers: Vec<(&'static str, &'static str)>,
+    pub(crate) body: &'static str,
+}
+
+/// A local HTTP stand-in answering one request per status with it and
+/// `body`, the task returns the head and body of the requests it got
+pub(crate) async fn serve(
+    statuses: Vec<u16>,
+    body: &'static str,
+) -> (String, JoinHandle<Vec<(String, String)>>) {
+    serve_replies(
+        statuses
+            .into_iter()
+            .map(|status| Reply {
+                status,
+                headers: Vec::new(),
+                body,
+            })
+            .collect(),
+    )
+    .await
+}
+
+/// Like `serve`, answering the requests with `replies` in order
+pub(crate) async fn serve_replies(
+    replies: Vec<Reply>,
+) -> (String, JoinHandle<Vec<(String, String)>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+
+    let server = tokio::spawn(async move {
+        let mut requests = Vec::new();
+
+        for reply in replies {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+
+            let mut head = String::new();
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                head += &line;
+            }
+
+            let length = head
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                .map_or(0, |(_, value)| value.trim().parse().unwrap());
+            let mut received = vec![0; length];
+            stream.read_exact(&mut received).await.unwrap();
+
+            let headers = reply
+                .headers
+                .iter()
+                .map(|(name, value)| format!("{}: {}\r\n", name, value))
+                .collect::<String>();
+            let response = format!(
+                "HTTP/1.1 {} \r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                reply.status,
+                headers,
+                reply.body.len(),
+                reply.body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            stream.shutdown().await.unwrap();
+
+            requests.push((head, String::from_utf8(received).unwrap()));
+        }
+
+        requests
+    });
+
+    (url, server)
+}